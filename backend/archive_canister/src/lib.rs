@@ -0,0 +1,143 @@
+use candid::{Nat, Principal};
+use ic_cdk_macros::*;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell};
+use scholar_common::{ArchivedBlock, BlockWithId, GetBlocksArgs, GetBlocksResult, TokenTransaction, TransactionType};
+
+use std::cell::RefCell;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const MAX_BLOCKS_PER_REQUEST: u64 = 100;
+const MAX_TRANSACTIONS_PER_REQUEST: usize = 500;
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    // The only principal allowed to append blocks.
+    static LEDGER: RefCell<StableCell<Principal, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
+            Principal::anonymous(),
+        ).expect("failed to initialize ledger id")
+    );
+
+    static BLOCKS: RefCell<StableBTreeMap<u64, ArchivedBlock, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
+        )
+    );
+}
+
+#[init]
+fn init(ledger_id: Principal) {
+    LEDGER.with(|ledger| {
+        ledger.borrow_mut().set(ledger_id).expect("failed to store ledger id");
+    });
+}
+
+// Called by the ledger with the oldest blocks it still holds. Blocks already
+// stored are skipped, so the ledger can resend a batch whose reply it lost.
+#[update]
+pub fn append_blocks(blocks: Vec<ArchivedBlock>) -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    if caller != LEDGER.with(|ledger| *ledger.borrow().get()) {
+        return Err("Only the ledger can append blocks".to_string());
+    }
+
+    BLOCKS.with(|stored| {
+        let mut stored_map = stored.borrow_mut();
+        let mut next = stored_map.last_key_value().map(|(id, _)| id + 1);
+        for block in blocks {
+            if next.is_some_and(|next| block.id < next) {
+                continue;
+            }
+            if next.is_some_and(|next| block.id != next) {
+                return Err(format!("Expected block {}, got {}", next.unwrap_or(0), block.id));
+            }
+            next = Some(block.id + 1);
+            stored_map.insert(block.id, block);
+        }
+        Ok(next.unwrap_or(0))
+    })
+}
+
+#[query]
+pub fn icrc3_get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    BLOCKS.with(|blocks| {
+        let blocks_map = blocks.borrow();
+        let log_length = blocks_map.last_key_value().map(|(id, _)| id + 1).unwrap_or(0);
+
+        let mut result = Vec::new();
+        for arg in args {
+            let start = u64::try_from(&arg.start.0).unwrap_or(u64::MAX);
+            let length = u64::try_from(&arg.length.0).unwrap_or(u64::MAX).min(MAX_BLOCKS_PER_REQUEST);
+            for (id, block) in blocks_map.range(start..start.saturating_add(length)) {
+                if result.len() as u64 >= MAX_BLOCKS_PER_REQUEST {
+                    break;
+                }
+                result.push(BlockWithId {
+                    id: Nat::from(id),
+                    block: block.value,
+                });
+            }
+        }
+
+        GetBlocksResult {
+            log_length: Nat::from(log_length),
+            blocks: result,
+            archived_blocks: Vec::new(),
+        }
+    })
+}
+
+// Raw blocks for the ledger, e.g. to replay them during an audit.
+#[query]
+pub fn get_blocks(start: u64, length: u64) -> Vec<ArchivedBlock> {
+    let length = length.min(MAX_BLOCKS_PER_REQUEST);
+    BLOCKS.with(|blocks| {
+        blocks
+            .borrow()
+            .range(start..start.saturating_add(length))
+            .map(|(_, block)| block)
+            .collect()
+    })
+}
+
+// Transactions at the given block indices, as listed by the ledger's
+// `get_transaction_history`.
+#[query]
+pub fn get_transactions(block_indices: Vec<u64>, transaction_type: Option<TransactionType>) -> Vec<TokenTransaction> {
+    BLOCKS.with(|blocks| {
+        let blocks_map = blocks.borrow();
+        block_indices
+            .iter()
+            .take(MAX_TRANSACTIONS_PER_REQUEST)
+            .filter_map(|index| blocks_map.get(index))
+            .map(|block| block.block.transaction)
+            .filter(|transaction| match &transaction_type {
+                Some(wanted) => *wanted == transaction.transaction_type,
+                None => true,
+            })
+            .collect()
+    })
+}
+
+// First and last block index held here.
+#[query]
+pub fn get_block_range() -> Option<(u64, u64)> {
+    BLOCKS.with(|blocks| {
+        let blocks_map = blocks.borrow();
+        let (first, _) = blocks_map.first_key_value()?;
+        let (last, _) = blocks_map.last_key_value()?;
+        Some((first, last))
+    })
+}
+
+#[query]
+pub fn get_ledger_id() -> Principal {
+    LEDGER.with(|ledger| *ledger.borrow().get())
+}
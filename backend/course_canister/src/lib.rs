@@ -4,11 +4,15 @@ use ic_cdk_macros::*;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::storable::{Bound, Storable};
 
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell};
+use scholar_common::{raw_rand_salt, CourseStake, IdAllocator};
 
 use serde::Serialize;
 use std::borrow::Cow;
 use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::time::Duration;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
@@ -31,6 +35,9 @@ pub struct Course {
     pub updated_at: u64,
     pub published: bool,
     pub token_reward: u64,
+    // Tokens a student pays to enroll; free when unset or zero. Optional so
+    // courses stored before pricing existed still decode.
+    pub price: Option<u64>,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
@@ -43,10 +50,17 @@ pub struct EducatorProfile {
     pub updated_at: u64,
 }
 
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RankedCourse {
+    pub course: Course,
+    pub total_staked: u64,
+    pub stakers: u64,
+}
+
 impl Storable for Course {
     const BOUND: Bound = Bound::Bounded { max_size: 8192, is_fixed_size: false };
 
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(candid::encode_one(self).unwrap())
     }
 
@@ -59,7 +73,7 @@ impl Storable for Course {
 impl Storable for EducatorProfile {
     const BOUND: Bound = Bound::Bounded { max_size: 2048, is_fixed_size: false };
 
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(candid::encode_one(self).unwrap())
     }
 
@@ -85,6 +99,18 @@ thread_local! {
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
         )
     );
+
+    static ID_COUNTERS: RefCell<IdAllocator<Memory>> = RefCell::new(
+        IdAllocator::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))),
+    );
+
+    // Ledger that course stakes are read from; anonymous until configured.
+    static TOKEN_CANISTER: RefCell<StableCell<Principal, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
+            Principal::anonymous(),
+        ).expect("failed to initialize token canister id")
+    );
 }
 
 #[update]
@@ -119,10 +145,9 @@ pub fn get_educator_profile(principal: Option<Principal>) -> Result<EducatorProf
 }
 
 #[update]
-pub fn create_course(title: String, description: String, token_reward: u64) -> Result<Course, String> {
+pub fn create_course(title: String, description: String, token_reward: u64, price: Option<u64>) -> Result<Course, String> {
     let caller = ic_cdk::caller();
     let now = time();
-    let course_id = format!("{}_{}", caller.to_text(), now);
     
     // Check if educator profile exists
     EDUCATOR_PROFILES.with(|profiles| {
@@ -132,6 +157,7 @@ pub fn create_course(title: String, description: String, token_reward: u64) -> R
         Ok(())
     })?;
     
+    let course_id = next_id("course");
     let course = Course {
         id: course_id.clone(),
         title,
@@ -142,6 +168,7 @@ pub fn create_course(title: String, description: String, token_reward: u64) -> R
         updated_at: now,
         published: false,
         token_reward,
+        price,
     };
     
     COURSES.with(|courses| {
@@ -207,6 +234,26 @@ pub fn publish_course(course_id: String) -> Result<Course, String> {
     })
 }
 
+#[update]
+pub fn set_course_price(course_id: String, price: Option<u64>) -> Result<Course, String> {
+    let caller = ic_cdk::caller();
+    let now = time();
+
+    COURSES.with(|courses| {
+        let mut courses_map = courses.borrow_mut();
+        let mut course = courses_map.get(&course_id)
+            .ok_or_else(|| "Course not found".to_string())?;
+        if course.educator_id != caller {
+            return Err("Only the course creator can set the price".to_string());
+        }
+
+        course.price = price;
+        course.updated_at = now;
+        courses_map.insert(course_id, course.clone());
+        Ok(course)
+    })
+}
+
 #[query]
 pub fn get_course(course_id: String) -> Result<Course, String> {
     COURSES.with(|courses| {
@@ -235,4 +282,75 @@ pub fn get_educator_courses() -> Vec<Course> {
             .map(|(_, course)| course)
             .collect()
     })
-}
\ No newline at end of file
+}
+
+// Published courses ordered by how much is staked on them, most first.
+#[query(composite = true)]
+pub async fn get_ranked_courses() -> Result<Vec<RankedCourse>, String> {
+    let token_canister = get_token_canister().ok_or_else(|| "Token canister is not configured".to_string())?;
+    let courses = get_published_courses();
+    let course_ids: Vec<String> = courses.iter().map(|course| course.id.clone()).collect();
+
+    let (stakes,): (Vec<CourseStake>,) = ic_cdk::call(token_canister, "get_course_stakes", (course_ids,))
+        .await
+        .map_err(|(code, msg)| format!("Failed to fetch course stakes: {:?} {}", code, msg))?;
+    let stakes: HashMap<String, CourseStake> = stakes.into_iter().map(|stake| (stake.course_id.clone(), stake)).collect();
+
+    let mut ranked: Vec<RankedCourse> = courses
+        .into_iter()
+        .map(|course| {
+            let stake = stakes.get(&course.id);
+            RankedCourse {
+                total_staked: stake.map(|s| s.total_staked).unwrap_or(0),
+                stakers: stake.map(|s| s.stakers).unwrap_or(0),
+                course,
+            }
+        })
+        .collect();
+    ranked.sort_by_key(|ranked| Reverse(ranked.total_staked));
+    Ok(ranked)
+}
+
+#[update]
+pub fn set_token_canister(token_canister: Principal) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Only controllers can set the token canister".to_string());
+    }
+    TOKEN_CANISTER.with(|cell| {
+        cell.borrow_mut().set(token_canister).expect("failed to store token canister id");
+    });
+    Ok(())
+}
+
+#[query]
+pub fn get_token_canister() -> Option<Principal> {
+    let token_canister = TOKEN_CANISTER.with(|cell| *cell.borrow().get());
+    (token_canister != Principal::anonymous()).then_some(token_canister)
+}
+
+fn next_id(kind: &str) -> String {
+    ID_COUNTERS.with(|counters| counters.borrow_mut().next_id(kind))
+}
+
+// Salts course and section IDs with fresh randomness. init can't await
+// raw_rand, so it runs from a timer straight after.
+fn schedule_id_salt() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        ic_cdk::spawn(async {
+            match raw_rand_salt().await {
+                Ok(salt) => ID_COUNTERS.with(|counters| counters.borrow_mut().set_salt(Some(salt))),
+                Err(message) => ic_cdk::print(format!("ID salt not set: {}", message)),
+            }
+        })
+    });
+}
+
+#[init]
+fn init() {
+    schedule_id_salt();
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    schedule_id_salt();
+}
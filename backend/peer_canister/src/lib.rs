@@ -4,11 +4,13 @@ use ic_cdk_macros::*;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::storable::{Bound, Storable};
 
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell};
+use scholar_common::{memo_for, raw_rand_salt, IdAllocator, TokenError, TokenTransaction};
 
 use serde::Serialize;
 use std::borrow::Cow;
 use std::cell::RefCell;
+use std::time::Duration;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
@@ -42,6 +44,9 @@ pub struct PeerTip {
     pub amount: u64,
     pub timestamp: u64,
     pub message: String,
+    // Ledger transaction that paid the tip. Tips recorded before tipping
+    // moved tokens have none.
+    pub transaction_id: Option<String>,
 }
 
 impl Storable for PeerNote {
@@ -50,7 +55,7 @@ impl Storable for PeerNote {
         is_fixed_size: false,
     };
 
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(candid::encode_one(self).unwrap())
     }
 
@@ -65,7 +70,7 @@ impl Storable for PeerTip {
         is_fixed_size: false,
     };
 
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(candid::encode_one(self).unwrap())
     }
 
@@ -91,13 +96,25 @@ thread_local! {
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
         )
     );
+
+    static ID_COUNTERS: RefCell<IdAllocator<Memory>> = RefCell::new(
+        IdAllocator::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))),
+    );
+
+    // Ledger that tips are paid through; anonymous until configured.
+    static TOKEN_CANISTER: RefCell<StableCell<Principal, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
+            Principal::anonymous(),
+        ).expect("failed to initialize token canister id")
+    );
 }
 
 #[update]
 pub fn create_peer_note(course_id: String, author_name: String, content: String, note_type: NoteType) -> Result<PeerNote, String> {
     let caller = ic_cdk::caller();
     let now = time();
-    let note_id = format!("{}_{}", caller.to_text(), now);
+    let note_id = next_id("note");
     
     let note = PeerNote {
         id: note_id.clone(),
@@ -140,11 +157,13 @@ pub fn update_peer_note(note_id: String, content: String) -> Result<PeerNote, St
     })
 }
 
+// Pays the author through token_canister, which needs an ICRC-2 approval
+// from the tipper for this canister covering the amount plus the fee.
 #[update]
-pub fn tip_peer_note(note_id: String, amount: u64, message: String) -> Result<PeerTip, String> {
+pub async fn tip_peer_note(note_id: String, amount: u64, message: String) -> Result<PeerTip, String> {
     let caller = ic_cdk::caller();
     let now = time();
-    let tip_id = format!("{}_{}", caller.to_text(), now);
+    let token_canister = get_token_canister().ok_or("Token canister is not configured")?;
     
     // Get the note to find the recipient
     let note = PEER_NOTES.with(|notes| {
@@ -156,26 +175,44 @@ pub fn tip_peer_note(note_id: String, amount: u64, message: String) -> Result<Pe
         return Err("Cannot tip your own note".to_string());
     }
     
+    // Count the tip right away so it shows while the transfer is in flight;
+    // undone below if the transfer fails.
+    add_tips_received(&note_id, amount as i128);
+    
+    // Note IDs can be longer than a ledger memo. No `created_at_time`, so
+    // tipping the same note twice with the same amount isn't taken for a
+    // retry of the first tip.
+    let memo = memo_for("Tip", &note_id);
+    let result: Result<(Result<TokenTransaction, TokenError>,), _> = ic_cdk::call(
+        token_canister,
+        "tip_peer",
+        (note.author, amount, memo, None::<u64>, Some(caller)),
+    ).await;
+    let transaction = match result {
+        Ok((Ok(transaction),)) => transaction,
+        Ok((Err(err),)) => {
+            add_tips_received(&note_id, -(amount as i128));
+            return Err(format!("Tip transfer failed: {:?}", err));
+        }
+        Err((code, message)) => {
+            add_tips_received(&note_id, -(amount as i128));
+            return Err(format!("Tip transfer failed: {:?} {}", code, message));
+        }
+    };
+    
     // Create tip record
+    let tip_id = next_id("tip");
     let tip = PeerTip {
         id: tip_id.clone(),
-        note_id: note_id.clone(),
+        note_id,
         tipper: caller,
         recipient: note.author,
         amount,
         timestamp: now,
         message,
+        transaction_id: Some(transaction.id),
     };
     
-    // Update note's tips received
-    PEER_NOTES.with(|notes| {
-        let mut notes_map = notes.borrow_mut();
-        if let Some(mut note) = notes_map.get(&note_id) {
-            note.tips_received += amount;
-            notes_map.insert(note_id, note);
-        }
-    });
-    
     // Store tip
     PEER_TIPS.with(|tips| {
         tips.borrow_mut().insert(tip_id, tip.clone());
@@ -226,4 +263,58 @@ pub fn get_user_tips_received(principal: Option<Principal>) -> Vec<PeerTip> {
             .map(|(_, tip)| tip)
             .collect()
     })
-}
\ No newline at end of file
+}
+
+fn next_id(kind: &str) -> String {
+    ID_COUNTERS.with(|counters| counters.borrow_mut().next_id(kind))
+}
+
+// Note and tip IDs get a random salt once the canister is up; raw_rand
+// can only be awaited outside init.
+fn schedule_id_salt() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        ic_cdk::spawn(async {
+            match raw_rand_salt().await {
+                Ok(salt) => ID_COUNTERS.with(|counters| counters.borrow_mut().set_salt(Some(salt))),
+                Err(message) => ic_cdk::print(format!("ID salt not set: {}", message)),
+            }
+        })
+    });
+}
+
+#[init]
+fn init() {
+    schedule_id_salt();
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    schedule_id_salt();
+}
+
+#[update]
+pub fn set_token_canister(token_canister: Principal) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Only controllers can set the token canister".to_string());
+    }
+    TOKEN_CANISTER.with(|cell| {
+        cell.borrow_mut().set(token_canister).expect("failed to store token canister id");
+    });
+    Ok(())
+}
+
+#[query]
+pub fn get_token_canister() -> Option<Principal> {
+    let token_canister = TOKEN_CANISTER.with(|cell| *cell.borrow().get());
+    (token_canister != Principal::anonymous()).then_some(token_canister)
+}
+
+fn add_tips_received(note_id: &str, delta: i128) {
+    PEER_NOTES.with(|notes| {
+        let mut notes_map = notes.borrow_mut();
+        if let Some(mut note) = notes_map.get(&note_id.to_string()) {
+            note.tips_received = (note.tips_received as i128 + delta).clamp(0, u64::MAX as i128) as u64;
+            notes_map.insert(note_id.to_string(), note);
+        }
+    });
+}
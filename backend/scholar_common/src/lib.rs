@@ -0,0 +1,786 @@
+// Types and helpers shared by the scholar canisters. The ledger's wire types
+// live here so the archive, student and peer canisters decode exactly what
+// token_canister sends.
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_stable_structures::storable::{Bound, Storable};
+use ic_stable_structures::{Memory, StableBTreeMap};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+
+pub type Subaccount = [u8; 32];
+
+pub type Hash = [u8; 32];
+
+pub const DEFAULT_SUBACCOUNT: Subaccount = [0; 32];
+
+// Longest memo the ledger accepts, in bytes.
+pub const MAX_MEMO_LENGTH: usize = 32;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+impl Account {
+    pub fn new(owner: Principal) -> Self {
+        Account { owner, subaccount: None }
+    }
+
+    pub fn effective_subaccount(&self) -> &Subaccount {
+        self.subaccount.as_ref().unwrap_or(&DEFAULT_SUBACCOUNT)
+    }
+}
+
+// `None` and the all-zero subaccount name the same account.
+impl PartialEq for Account {
+    fn eq(&self, other: &Self) -> bool {
+        self.owner == other.owner && self.effective_subaccount() == other.effective_subaccount()
+    }
+}
+
+impl Eq for Account {}
+
+impl PartialOrd for Account {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Account {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.owner
+            .cmp(&other.owner)
+            .then_with(|| self.effective_subaccount().cmp(other.effective_subaccount()))
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct TokenTransaction {
+    pub id: String,
+    pub from: Principal,
+    pub to: Principal,
+    pub amount: u64,
+    pub transaction_type: TransactionType,
+    pub timestamp: u64,
+    pub memo: String,
+    pub from_subaccount: Option<Subaccount>,
+    pub to_subaccount: Option<Subaccount>,
+    pub spender: Option<Account>,
+    pub fee: Option<u64>,
+    // Shared by every leg of a batch transfer.
+    pub batch_id: Option<String>,
+    // Account the fee went to; fees without one were burned.
+    pub fee_collector: Option<Account>,
+    // Set on SetMetadata blocks.
+    pub metadata: Option<MetadataChange>,
+    // An ICRC memo that is not valid UTF-8, exactly as it was sent; `memo`
+    // then only holds a readable copy.
+    pub raw_memo: Option<Vec<u8>>,
+}
+
+impl TokenTransaction {
+    // The memo bytes the block records and hashes.
+    pub fn memo_bytes(&self) -> &[u8] {
+        self.raw_memo.as_deref().unwrap_or(self.memo.as_bytes())
+    }
+}
+
+// The metadata a SetMetadata block changed, as it was set. The logo and
+// description are too large to log, so only their SHA-256 is kept; the hash
+// of the empty string means the field was cleared.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct MetadataChange {
+    pub name: Option<String>,
+    pub symbol: Option<String>,
+    pub logo_hash: Option<Hash>,
+    pub description_hash: Option<Hash>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum TransactionType {
+    Mint,
+    Transfer,
+    CourseReward,
+    PeerTip,
+    Approve,
+    TransferFrom,
+    AddMinter,
+    RemoveMinter,
+    Burn,
+    HoldCreate,
+    HoldRelease,
+    HoldRefund,
+    VestingRelease,
+    Stake,
+    Unstake,
+    CuratorReward,
+    SetFeeCollector,
+    SetMetadata,
+}
+
+// A ledger block: one transaction plus the hash of the block before it.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Block {
+    pub parent_hash: Option<Hash>,
+    pub transaction: TokenTransaction,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TokenError {
+    Unauthorized { caller: Principal },
+    InsufficientFunds { balance: u64 },
+    InsufficientAllowance { allowance: u64 },
+    BadBurn { min_burn_amount: u64 },
+    // The operation would push a balance or the total supply past u64::MAX.
+    Overflow,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u64 },
+    HoldNotFound { hold_id: u64 },
+    HoldNotActive { status: HoldStatus },
+    MintCapExceeded { remaining: u64 },
+    SnapshotNotFound { snapshot_id: u64 },
+    AccountFrozen { principal: Principal, reason: FreezeReason },
+    MemoTooLong { max_length: u64 },
+    GenericError { message: String },
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HoldStatus {
+    Active,
+    Released,
+    Refunded,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FreezeReason {
+    SuspectedFraud,
+    RewardFarming,
+    CompromisedAccount,
+    ComplianceReview,
+    Other,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BatchTransferResult {
+    pub batch_id: String,
+    pub total_amount: u64,
+    pub total_fee: u64,
+    pub transactions: Vec<TokenTransaction>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApproveArgs {
+    pub from_subaccount: Option<Subaccount>,
+    pub spender: Account,
+    pub amount: Nat,
+    pub expected_allowance: Option<Nat>,
+    pub expires_at: Option<u64>,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ApproveError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+    AllowanceChanged { current_allowance: Nat },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AllowanceArgs {
+    pub account: Account,
+    pub spender: Account,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AllowanceResponse {
+    pub allowance: Nat,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum ICRC3Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(candid::Int),
+    Array(Vec<ICRC3Value>),
+    Map(Vec<(String, ICRC3Value)>),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct GetBlocksArgs {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct BlockWithId {
+    pub id: Nat,
+    pub block: ICRC3Value,
+}
+
+candid::define_function!(pub GetBlocksCallback : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchivedBlocks {
+    pub args: Vec<GetBlocksArgs>,
+    pub callback: GetBlocksCallback,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksResult {
+    pub log_length: Nat,
+    pub blocks: Vec<BlockWithId>,
+    pub archived_blocks: Vec<ArchivedBlocks>,
+}
+
+// A block as handed to the archive, together with its ICRC-3 value so the
+// archive can serve `icrc3_get_blocks` without knowing how to encode it.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ArchivedBlock {
+    pub id: u64,
+    pub block: Block,
+    pub value: ICRC3Value,
+}
+
+// Stake totals for a course, as returned by the ledger's `get_course_stakes`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CourseStake {
+    pub course_id: String,
+    pub total_staked: u64,
+    pub stakers: u64,
+    pub pending_rewards: u64,
+}
+
+// Default-subaccount keys are just the owner's bytes, so balances stored
+// before subaccounts existed are still found under `Account::new(owner)`.
+impl Storable for Account {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 29 + 32,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.owner.as_slice().to_vec();
+        if self.effective_subaccount() != &DEFAULT_SUBACCOUNT {
+            bytes.extend_from_slice(self.effective_subaccount());
+        }
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        if bytes.len() > 29 {
+            let (owner, subaccount) = bytes.split_at(bytes.len() - 32);
+            let mut sub = DEFAULT_SUBACCOUNT;
+            sub.copy_from_slice(subaccount);
+            Account {
+                owner: Principal::from_slice(owner),
+                subaccount: Some(sub),
+            }
+        } else {
+            Account::new(Principal::from_slice(&bytes))
+        }
+    }
+}
+
+impl Storable for TokenTransaction {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1024,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+impl Storable for Block {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1280,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+impl Storable for ArchivedBlock {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 4096,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+impl TransactionType {
+    // ICRC-3 block type; rewards and tips are recorded as the standard
+    // mint and transfer blocks they are economically equivalent to.
+    pub fn btype(&self) -> &'static str {
+        match self {
+            TransactionType::Mint | TransactionType::CourseReward => "1mint",
+            TransactionType::Transfer | TransactionType::PeerTip => "1xfer",
+            TransactionType::Approve => "2approve",
+            TransactionType::TransferFrom => "2xfer",
+            TransactionType::AddMinter => "scholar_minter_add",
+            TransactionType::RemoveMinter => "scholar_minter_remove",
+            TransactionType::Burn => "1burn",
+            TransactionType::HoldCreate => "scholar_hold_create",
+            TransactionType::HoldRelease => "scholar_hold_release",
+            TransactionType::HoldRefund => "scholar_hold_refund",
+            TransactionType::VestingRelease => "scholar_vesting_release",
+            TransactionType::Stake => "scholar_stake",
+            TransactionType::Unstake => "scholar_unstake",
+            TransactionType::CuratorReward => "scholar_curator_reward",
+            TransactionType::SetFeeCollector => "scholar_fee_collector",
+            TransactionType::SetMetadata => "scholar_metadata",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransactionType::Mint => "mint",
+            TransactionType::Transfer => "transfer",
+            TransactionType::CourseReward => "course_reward",
+            TransactionType::PeerTip => "peer_tip",
+            TransactionType::Approve => "approve",
+            TransactionType::TransferFrom => "transfer_from",
+            TransactionType::AddMinter => "add_minter",
+            TransactionType::RemoveMinter => "remove_minter",
+            TransactionType::Burn => "burn",
+            TransactionType::HoldCreate => "hold_create",
+            TransactionType::HoldRelease => "hold_release",
+            TransactionType::HoldRefund => "hold_refund",
+            TransactionType::VestingRelease => "vesting_release",
+            TransactionType::Stake => "stake",
+            TransactionType::Unstake => "unstake",
+            TransactionType::CuratorReward => "curator_reward",
+            TransactionType::SetFeeCollector => "set_fee_collector",
+            TransactionType::SetMetadata => "set_metadata",
+        }
+    }
+}
+
+impl Block {
+    pub fn to_value(&self) -> ICRC3Value {
+        let tx = &self.transaction;
+        let from = Account {
+            owner: tx.from,
+            subaccount: tx.from_subaccount,
+        };
+        let to = Account {
+            owner: tx.to,
+            subaccount: tx.to_subaccount,
+        };
+
+        let mut tx_fields = vec![
+            ("amt".to_string(), ICRC3Value::Nat(Nat::from(tx.amount))),
+            ("kind".to_string(), ICRC3Value::Text(tx.transaction_type.name().to_string())),
+        ];
+        match tx.transaction_type {
+            TransactionType::Mint | TransactionType::CourseReward => {
+                tx_fields.push(("minter".to_string(), ICRC3Value::Blob(tx.from.as_slice().to_vec())));
+                tx_fields.push(("to".to_string(), account_value(&to)));
+            }
+            TransactionType::Approve | TransactionType::Burn => {
+                tx_fields.push(("from".to_string(), account_value(&from)));
+            }
+            TransactionType::AddMinter | TransactionType::RemoveMinter => {
+                tx_fields.push(("admin".to_string(), ICRC3Value::Blob(tx.from.as_slice().to_vec())));
+                tx_fields.push(("principal".to_string(), ICRC3Value::Blob(tx.to.as_slice().to_vec())));
+            }
+            TransactionType::SetMetadata => {
+                tx_fields.push(("admin".to_string(), ICRC3Value::Blob(tx.from.as_slice().to_vec())));
+                if let Some(change) = &tx.metadata {
+                    if let Some(name) = &change.name {
+                        tx_fields.push(("name".to_string(), ICRC3Value::Text(name.clone())));
+                    }
+                    if let Some(symbol) = &change.symbol {
+                        tx_fields.push(("symbol".to_string(), ICRC3Value::Text(symbol.clone())));
+                    }
+                    if let Some(logo_hash) = change.logo_hash {
+                        tx_fields.push(("logo_hash".to_string(), ICRC3Value::Blob(logo_hash.to_vec())));
+                    }
+                    if let Some(description_hash) = change.description_hash {
+                        tx_fields.push(("description_hash".to_string(), ICRC3Value::Blob(description_hash.to_vec())));
+                    }
+                }
+            }
+            _ => {
+                tx_fields.push(("from".to_string(), account_value(&from)));
+                tx_fields.push(("to".to_string(), account_value(&to)));
+            }
+        }
+        if let Some(spender) = &tx.spender {
+            tx_fields.push(("spender".to_string(), account_value(spender)));
+        }
+        if !tx.memo_bytes().is_empty() {
+            tx_fields.push(("memo".to_string(), ICRC3Value::Blob(tx.memo_bytes().to_vec())));
+        }
+        if let Some(batch_id) = &tx.batch_id {
+            tx_fields.push(("batch".to_string(), ICRC3Value::Text(batch_id.clone())));
+        }
+
+        let mut fields = vec![
+            ("btype".to_string(), ICRC3Value::Text(tx.transaction_type.btype().to_string())),
+            ("ts".to_string(), ICRC3Value::Nat(Nat::from(tx.timestamp))),
+            ("tx".to_string(), ICRC3Value::Map(tx_fields)),
+        ];
+        if let Some(parent_hash) = self.parent_hash {
+            fields.push(("phash".to_string(), ICRC3Value::Blob(parent_hash.to_vec())));
+        }
+        if let Some(fee) = tx.fee.filter(|fee| *fee > 0) {
+            fields.push(("fee".to_string(), ICRC3Value::Nat(Nat::from(fee))));
+        }
+        if let Some(fee_collector) = &tx.fee_collector {
+            fields.push(("fee_col".to_string(), account_value(fee_collector)));
+        }
+        ICRC3Value::Map(fields)
+    }
+
+    pub fn hash(&self) -> Hash {
+        self.to_value().hash()
+    }
+}
+
+impl ICRC3Value {
+    // Representation-independent hash as specified by ICRC-3.
+    pub fn hash(&self) -> Hash {
+        let mut hasher = Sha256::new();
+        match self {
+            ICRC3Value::Blob(bytes) => hasher.update(bytes),
+            ICRC3Value::Text(text) => hasher.update(text.as_bytes()),
+            ICRC3Value::Nat(nat) => {
+                let mut buf = vec![];
+                nat.encode(&mut buf).expect("LEB128 encoding of a nat cannot fail");
+                hasher.update(buf);
+            }
+            ICRC3Value::Int(int) => {
+                let mut buf = vec![];
+                int.encode(&mut buf).expect("SLEB128 encoding of an int cannot fail");
+                hasher.update(buf);
+            }
+            ICRC3Value::Array(values) => {
+                for value in values {
+                    hasher.update(value.hash());
+                }
+            }
+            ICRC3Value::Map(fields) => {
+                let mut entries: Vec<Vec<u8>> = fields
+                    .iter()
+                    .map(|(key, value)| {
+                        let mut entry = Sha256::digest(key.as_bytes()).to_vec();
+                        entry.extend_from_slice(&value.hash());
+                        entry
+                    })
+                    .collect();
+                entries.sort();
+                for entry in entries {
+                    hasher.update(entry);
+                }
+            }
+        }
+        hasher.finalize().into()
+    }
+}
+
+pub fn account_value(account: &Account) -> ICRC3Value {
+    let mut parts = vec![ICRC3Value::Blob(account.owner.as_slice().to_vec())];
+    if account.effective_subaccount() != &DEFAULT_SUBACCOUNT {
+        parts.push(ICRC3Value::Blob(account.effective_subaccount().to_vec()));
+    }
+    ICRC3Value::Array(parts)
+}
+
+// A ledger memo pointing at an entity whose ID may not fit in one: the
+// label and the first 8 bytes of the ID's SHA-256 in hex. Labels up to 15
+// bytes keep it within MAX_MEMO_LENGTH.
+pub fn memo_for(label: &str, id: &str) -> String {
+    let digest = Sha256::digest(id.as_bytes());
+    let tag: String = digest[..8].iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{} {}", label, tag)
+}
+
+// Per-kind counters behind every generated ID. IDs come from here rather
+// than from the caller and timestamp, which repeat within a single round.
+//
+// Once a salt is set, IDs also carry a tag derived from it, so they can't be
+// guessed from the sequence alone. The sequence still makes them unique.
+pub struct IdAllocator<M: Memory> {
+    counters: StableBTreeMap<String, u64, M>,
+}
+
+// Kinds are fixed strings chosen by the canisters, none of them starting
+// with '#'.
+const SALT_KEY: &str = "#salt";
+
+impl<M: Memory> IdAllocator<M> {
+    pub fn init(memory: M) -> Self {
+        IdAllocator {
+            counters: StableBTreeMap::init(memory),
+        }
+    }
+
+    pub fn next_sequence(&mut self, kind: &str) -> u64 {
+        let sequence = self.counters.get(&kind.to_string()).unwrap_or(0);
+        self.counters.insert(kind.to_string(), sequence + 1);
+        sequence
+    }
+
+    pub fn next_id(&mut self, kind: &str) -> String {
+        let sequence = self.next_sequence(kind);
+        match self.salt() {
+            Some(salt) => format!("{}_{}_{:08x}", kind, sequence, id_tag(salt, kind, sequence)),
+            None => format!("{}_{}", kind, sequence),
+        }
+    }
+
+    pub fn salt(&self) -> Option<u64> {
+        self.counters.get(&SALT_KEY.to_string())
+    }
+
+    pub fn set_salt(&mut self, salt: Option<u64>) {
+        match salt {
+            Some(salt) => self.counters.insert(SALT_KEY.to_string(), salt),
+            None => self.counters.remove(&SALT_KEY.to_string()),
+        };
+    }
+}
+
+fn id_tag(salt: u64, kind: &str, sequence: u64) -> u32 {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.to_be_bytes());
+    hasher.update(kind.as_bytes());
+    hasher.update(sequence.to_be_bytes());
+    let digest = hasher.finalize();
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+}
+
+// A fresh salt from the management canister's randomness, for
+// `IdAllocator::set_salt`.
+pub async fn raw_rand_salt() -> Result<u64, String> {
+    let (bytes,) = raw_rand()
+        .await
+        .map_err(|(code, message)| format!("raw_rand failed: {:?} {}", code, message))?;
+    let mut salt = [0u8; 8];
+    salt.copy_from_slice(bytes.get(..8).ok_or("raw_rand returned too few bytes")?);
+    Ok(u64::from_be_bytes(salt))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::DefaultMemoryImpl;
+
+    #[test]
+    fn ids_are_sequential_per_kind() {
+        let mut ids = IdAllocator::init(DefaultMemoryImpl::default());
+        assert_eq!(ids.next_id("tx"), "tx_0");
+        assert_eq!(ids.next_id("tx"), "tx_1");
+        assert_eq!(ids.next_id("course"), "course_0");
+        assert_eq!(ids.next_sequence("tx"), 2);
+    }
+
+    #[test]
+    fn salted_ids_stay_unique_and_keep_their_sequence() {
+        let mut ids = IdAllocator::init(DefaultMemoryImpl::default());
+        ids.set_salt(Some(42));
+        let first = ids.next_id("note");
+        let second = ids.next_id("note");
+        assert!(first.starts_with("note_0_"));
+        assert!(second.starts_with("note_1_"));
+        assert_ne!(first, second);
+
+        ids.set_salt(None);
+        assert_eq!(ids.next_id("note"), "note_2");
+    }
+
+    fn hex(hash: Hash) -> String {
+        hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn transfer(amount: u64, parent_hash: Option<Hash>) -> Block {
+        let alice = Account::new(Principal::from_slice(&[1; 29]));
+        let bob = Account::new(Principal::from_slice(&[2; 29]));
+        Block {
+            parent_hash,
+            transaction: TokenTransaction {
+                id: format!("tx_{}", amount),
+                from: alice.owner,
+                to: bob.owner,
+                amount,
+                transaction_type: TransactionType::Transfer,
+                timestamp: 1,
+                memo: String::new(),
+                from_subaccount: None,
+                to_subaccount: None,
+                spender: None,
+                fee: Some(10),
+                batch_id: None,
+                fee_collector: None,
+                metadata: None,
+                raw_memo: None,
+            },
+        }
+    }
+
+    // Test vectors from the ICRC-3 specification.
+    #[test]
+    fn values_hash_as_specified() {
+        assert_eq!(
+            hex(ICRC3Value::Nat(Nat::from(42u64)).hash()),
+            "684888c0ebb17f374298b65ee2807526c066094c701bcc7ebbe1c1095f494fc1"
+        );
+        assert_eq!(
+            hex(ICRC3Value::Int(candid::Int::from(-42)).hash()),
+            "de5a6f78116eca62d7fc5ce159d23ae6b889b365a1739ad2cf36f925a140d0cc"
+        );
+        assert_eq!(
+            hex(ICRC3Value::Text("Hello, World!".to_string()).hash()),
+            "dffd6021bb2bd5b0af676290809ec3a53191dd81c7f70a4b28688a362182986f"
+        );
+        assert_eq!(
+            hex(ICRC3Value::Blob(vec![1, 2, 3, 4]).hash()),
+            "9f64a747e1b97f131fabb6b447296c9b6f0201e79fb3c5356e6c77e89b6a806a"
+        );
+        let array = ICRC3Value::Array(vec![
+            ICRC3Value::Nat(Nat::from(3u64)),
+            ICRC3Value::Text("foo".to_string()),
+            ICRC3Value::Blob(vec![5, 6]),
+        ]);
+        assert_eq!(
+            hex(array.hash()),
+            "514a04011caa503990d446b7dec5d79e19c221ae607fb08b2848c67734d468d6"
+        );
+    }
+
+    #[test]
+    fn map_hash_ignores_field_order() {
+        let a = ("a".to_string(), ICRC3Value::Nat(Nat::from(1u64)));
+        let b = ("b".to_string(), ICRC3Value::Text("x".to_string()));
+        assert_eq!(
+            ICRC3Value::Map(vec![a.clone(), b.clone()]).hash(),
+            ICRC3Value::Map(vec![b, a]).hash()
+        );
+    }
+
+    #[test]
+    fn blocks_chain_through_the_parent_hash() {
+        let first = transfer(1, None);
+        let second = transfer(2, Some(first.hash()));
+        let ICRC3Value::Map(fields) = second.to_value() else {
+            panic!("a block is a map");
+        };
+        let phash = fields
+            .iter()
+            .find(|(key, _)| key == "phash")
+            .map(|(_, value)| value);
+        assert!(matches!(phash, Some(ICRC3Value::Blob(hash)) if hash[..] == first.hash()));
+
+        // Rewriting the first block breaks the link to the second.
+        let rewritten = transfer(3, None);
+        assert_ne!(rewritten.hash(), first.hash());
+        assert_ne!(transfer(2, Some(rewritten.hash())).hash(), second.hash());
+        // The genesis block has no parent hash field at all.
+        let ICRC3Value::Map(fields) = first.to_value() else {
+            panic!("a block is a map");
+        };
+        assert!(fields.iter().all(|(key, _)| key != "phash"));
+    }
+
+    #[test]
+    fn binary_memos_are_hashed_as_sent() {
+        let mut block = transfer(1, None);
+        block.transaction.memo = String::from_utf8_lossy(&[0xff, 0x00]).into_owned();
+        block.transaction.raw_memo = Some(vec![0xff, 0x00]);
+        let ICRC3Value::Map(fields) = block.to_value() else {
+            panic!("a block is a map");
+        };
+        let Some((_, ICRC3Value::Map(tx))) = fields.iter().find(|(key, _)| key == "tx") else {
+            panic!("a block has a tx map");
+        };
+        let memo = tx
+            .iter()
+            .find(|(key, _)| key == "memo")
+            .map(|(_, value)| value);
+        assert!(matches!(memo, Some(ICRC3Value::Blob(bytes)) if bytes[..] == [0xff, 0x00]));
+
+        let mut other = block.clone();
+        other.transaction.raw_memo = Some(vec![0xfe, 0x00]);
+        assert_ne!(other.hash(), block.hash());
+    }
+}
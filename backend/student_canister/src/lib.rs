@@ -1,4 +1,4 @@
-use candid::{CandidType, Deserialize, Principal};
+use candid::{CandidType, Deserialize, Nat, Principal};
 use ic_cdk::api::time;
 use ic_cdk_macros::*;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
@@ -6,14 +6,21 @@ use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemor
 use ic_stable_structures::storable::{Bound, Storable};
 
 
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell};
+use scholar_common::{
+    memo_for, Account, BatchTransferResult, TokenError, TokenTransaction, TransferArg, TransferError, TransferFromArgs,
+    TransferFromError,
+};
 
 use serde::Serialize;
 use std::borrow::Cow;
 use std::cell::RefCell;
+use std::collections::BTreeSet;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
+const DEFAULT_EDUCATOR_SHARE_BPS: u64 = 8_000;
+
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 pub struct StudentProfile {
     pub principal: Principal,
@@ -32,6 +39,72 @@ pub struct Enrollment {
     pub progress: Vec<String>, // completed section IDs
     pub completed: bool,
     pub completed_at: Option<u64>,
+    // Set for paid courses.
+    pub payment: Option<CoursePayment>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct CoursePayment {
+    pub amount: u64,
+    pub educator_amount: u64,
+    pub treasury_amount: u64,
+    // Ledger block of the student's payment and the batch that paid out the
+    // educator and treasury shares.
+    pub payment_block: u64,
+    pub payout_batch_id: String,
+}
+
+// A refund owed to a student whose payment could neither be paid out nor
+// returned. It stays here until `retry_refund` gets it through.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct PendingRefund {
+    pub id: u64,
+    pub student: Principal,
+    pub course_id: String,
+    // What the student receives; the refund's fee is already taken off.
+    pub amount: u64,
+    // Reused on every attempt so the ledger rejects a refund that already
+    // went through as a duplicate.
+    pub created_at_time: u64,
+    pub last_error: String,
+}
+
+// Where course payments go. Paid enrollment fails until the canisters are set.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct PaymentConfig {
+    pub token_canister: Option<Principal>,
+    pub course_canister: Option<Principal>,
+    pub treasury: Option<Principal>,
+    // Share of each payment that goes to the educator; the rest goes to the
+    // treasury, less the payout fees.
+    pub educator_share_bps: u64,
+}
+
+impl Default for PaymentConfig {
+    fn default() -> Self {
+        PaymentConfig {
+            token_canister: None,
+            course_canister: None,
+            treasury: None,
+            educator_share_bps: DEFAULT_EDUCATOR_SHARE_BPS,
+        }
+    }
+}
+
+// The fields of course_canister's Course that enrollment and completion
+// need.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CourseListing {
+    pub id: String,
+    pub title: String,
+    pub educator_id: Principal,
+    pub sections: Vec<SectionListing>,
+    pub price: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SectionListing {
+    pub id: String,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
@@ -52,7 +125,7 @@ impl Storable for StudentProfile {
         is_fixed_size: false,
     };
 
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(candid::encode_one(self).unwrap())
     }
 
@@ -67,7 +140,37 @@ impl Storable for Enrollment {
         is_fixed_size: false,
     };
 
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+impl Storable for PaymentConfig {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 256,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+impl Storable for PendingRefund {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1024,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(candid::encode_one(self).unwrap())
     }
 
@@ -82,7 +185,7 @@ impl Storable for Certificate {
         is_fixed_size: false,
     };
 
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(candid::encode_one(self).unwrap())
     }
 
@@ -113,6 +216,23 @@ thread_local! {
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
         )
     );
+
+    static PAYMENT_CONFIG: RefCell<StableCell<PaymentConfig, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
+            PaymentConfig::default(),
+        ).expect("failed to initialize payment config")
+    );
+
+    static PENDING_REFUNDS: RefCell<StableBTreeMap<u64, PendingRefund, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+        )
+    );
+
+    // Enrollment keys with a payment in flight, so a second call cannot pay
+    // for the same course while the first is waiting on the ledger.
+    static PENDING_ENROLLMENTS: RefCell<BTreeSet<String>> = const { RefCell::new(BTreeSet::new()) };
 }
 
 #[update]
@@ -167,30 +287,37 @@ pub fn update_student_profile(name: Option<String>, email: Option<String>, bio:
     })
 }
 
+// Paid courses need an ICRC-2 approval for this canister covering the price
+// plus the ledger's transfer fee.
 #[update]
-pub fn enroll_in_course(course_id: String) -> Result<Enrollment, String> {
+pub async fn enroll_in_course(course_id: String) -> Result<Enrollment, String> {
     let caller = ic_cdk::caller();
-    let now = time();
     let enrollment_key = format!("{}_{}", caller.to_text(), course_id);
     
     // Check if already enrolled
+    if ENROLLMENTS.with(|enrollments| enrollments.borrow().contains_key(&enrollment_key)) {
+        return Err("Already enrolled in this course".to_string());
+    }
+    if !PENDING_ENROLLMENTS.with(|pending| pending.borrow_mut().insert(enrollment_key.clone())) {
+        return Err("Enrollment in this course is already in progress".to_string());
+    }
+
+    let result = pay_for_course(caller, &course_id).await;
+    PENDING_ENROLLMENTS.with(|pending| pending.borrow_mut().remove(&enrollment_key));
+
+    let enrollment = Enrollment {
+        student_id: caller,
+        course_id,
+        enrolled_at: time(),
+        progress: Vec::new(),
+        completed: false,
+        completed_at: None,
+        payment: result?,
+    };
     ENROLLMENTS.with(|enrollments| {
-        if enrollments.borrow().contains_key(&enrollment_key) {
-            return Err("Already enrolled in this course".to_string());
-        }
-        
-        let enrollment = Enrollment {
-            student_id: caller,
-            course_id,
-            enrolled_at: now,
-            progress: Vec::new(),
-            completed: false,
-            completed_at: None,
-        };
-        
         enrollments.borrow_mut().insert(enrollment_key, enrollment.clone());
-        Ok(enrollment)
-    })
+    });
+    Ok(enrollment)
 }
 
 #[update]
@@ -212,35 +339,62 @@ pub fn mark_section_complete(course_id: String, section_id: String) -> Result<En
     })
 }
 
+// Certificates back completion rewards, so one is only issued once the
+// student's enrollment covers every section of the course as listed by
+// course_canister. Completing a course again returns the same certificate.
+// The completion reward is claimed right away; if that fails the certificate
+// still stands and `claim_completion_reward` can be retried.
 #[update]
-pub fn complete_course(course_id: String, course_title: String) -> Result<Certificate, String> {
+pub async fn complete_course(course_id: String) -> Result<Certificate, String> {
     let caller = ic_cdk::caller();
     let enrollment_key = format!("{}_{}", caller.to_text(), course_id);
-    let now = time();
-    
+    let certificate_id = format!("{}_{}", caller.to_text(), course_id);
+
     // Get student profile
     let student_profile = STUDENT_PROFILES.with(|profiles| {
         profiles.borrow().get(&caller)
             .ok_or_else(|| "Student profile not found".to_string())
     })?;
-    
+    ENROLLMENTS.with(|enrollments| enrollments.borrow().get(&enrollment_key))
+        .ok_or_else(|| "Enrollment not found".to_string())?;
+    if let Some(certificate) = CERTIFICATES.with(|certificates| certificates.borrow().get(&certificate_id)) {
+        return Ok(certificate);
+    }
+
+    let course_canister = get_payment_config().course_canister.ok_or("Course canister is not configured")?;
+    let (course,): (Result<CourseListing, String>,) =
+        ic_cdk::call(course_canister, "get_course", (course_id.clone(),))
+            .await
+            .map_err(|(code, message)| format!("Failed to look up course: {:?} {}", code, message))?;
+    let course = course?;
+
+    // Re-read the enrollment, since progress may have changed during the call.
+    let mut enrollment = ENROLLMENTS.with(|enrollments| enrollments.borrow().get(&enrollment_key))
+        .ok_or_else(|| "Enrollment not found".to_string())?;
+    if course.sections.is_empty() {
+        return Err("Course has no sections to complete".to_string());
+    }
+    let remaining = course.sections.iter()
+        .filter(|section| !enrollment.progress.contains(&section.id))
+        .count();
+    if remaining > 0 {
+        return Err(format!("{} section(s) of this course are not complete", remaining));
+    }
+
     // Mark enrollment as completed
+    let now = time();
+    enrollment.completed = true;
+    enrollment.completed_at = Some(now);
     ENROLLMENTS.with(|enrollments| {
-        let mut enrollments_map = enrollments.borrow_mut();
-        if let Some(mut enrollment) = enrollments_map.get(&enrollment_key) {
-            enrollment.completed = true;
-            enrollment.completed_at = Some(now);
-            enrollments_map.insert(enrollment_key, enrollment);
-        }
+        enrollments.borrow_mut().insert(enrollment_key, enrollment);
     });
-    
+
     // Generate certificate
-    let certificate_id = format!("{}_{}", caller.to_text(), course_id);
     let certificate = Certificate {
         student_id: caller,
         course_id,
         student_name: student_profile.name,
-        course_title,
+        course_title: course.title,
         completion_date: now,
         certificate_id: certificate_id.clone(),
     };
@@ -248,10 +402,39 @@ pub fn complete_course(course_id: String, course_title: String) -> Result<Certif
     CERTIFICATES.with(|certificates| {
         certificates.borrow_mut().insert(certificate_id, certificate.clone());
     });
+
+    if let Err(message) = pay_completion_reward(caller, certificate.course_id.clone()).await {
+        ic_cdk::print(format!("completion reward for {} not paid: {}", certificate.certificate_id, message));
+    }
     
     Ok(certificate)
 }
 
+// Pays the reward for a completed course. A reward that was already paid
+// counts as claimed, so this is safe to call again.
+#[update]
+pub async fn claim_completion_reward(course_id: String) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let certificate_id = format!("{}_{}", caller.to_text(), course_id);
+    if !CERTIFICATES.with(|certificates| certificates.borrow().contains_key(&certificate_id)) {
+        return Err("Course has not been completed".to_string());
+    }
+    pay_completion_reward(caller, course_id).await
+}
+
+// token_canister only pays rewards to its minters, so this canister must be
+// registered as one. It checks the certificate with us before minting.
+async fn pay_completion_reward(student: Principal, course_id: String) -> Result<(), String> {
+    let token_canister = get_payment_config().token_canister.ok_or("Token canister is not configured")?;
+    let result: Result<(Result<TokenTransaction, TokenError>,), _> =
+        ic_cdk::call(token_canister, "reward_course_completion", (student, course_id)).await;
+    match result {
+        Ok((Ok(_),)) | Ok((Err(TokenError::Duplicate { .. }),)) => Ok(()),
+        Ok((Err(err),)) => Err(format!("Reward failed: {:?}", err)),
+        Err((code, message)) => Err(format!("Reward failed: {:?} {}", code, message)),
+    }
+}
+
 #[query]
 pub fn get_student_enrollments() -> Vec<Enrollment> {
     let caller = ic_cdk::caller();
@@ -274,4 +457,253 @@ pub fn get_student_certificates() -> Vec<Certificate> {
             .map(|(_, certificate)| certificate)
             .collect()
     })
-}
\ No newline at end of file
+}
+
+// Lets other canisters, such as token_canister when paying a completion
+// reward, check that a student finished a course.
+#[query]
+pub fn get_certificate(student: Principal, course_id: String) -> Option<Certificate> {
+    let certificate_id = format!("{}_{}", student.to_text(), course_id);
+    CERTIFICATES.with(|certificates| certificates.borrow().get(&certificate_id))
+}
+
+#[update]
+pub fn set_payment_config(config: PaymentConfig) -> Result<PaymentConfig, String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Only controllers can change the payment config".to_string());
+    }
+    if config.educator_share_bps > 10_000 {
+        return Err("Educator share cannot exceed 10000 basis points".to_string());
+    }
+
+    PAYMENT_CONFIG.with(|cell| {
+        cell.borrow_mut().set(config.clone()).expect("failed to store payment config");
+    });
+    Ok(config)
+}
+
+#[query]
+pub fn get_payment_config() -> PaymentConfig {
+    PAYMENT_CONFIG.with(|cell| cell.borrow().get().clone())
+}
+
+// Refunds the student could not be given when their enrollment failed.
+// Controllers see every student's.
+#[query]
+pub fn get_pending_refunds() -> Vec<PendingRefund> {
+    let caller = ic_cdk::caller();
+    let all = ic_cdk::api::is_controller(&caller);
+
+    PENDING_REFUNDS.with(|refunds| {
+        refunds.borrow().iter()
+            .filter(|(_, refund)| all || refund.student == caller)
+            .map(|(_, refund)| refund)
+            .collect()
+    })
+}
+
+// Tries a pending refund again. Either the student it is owed to or a
+// controller may retry it.
+#[update]
+pub async fn retry_refund(refund_id: u64) -> Result<PendingRefund, String> {
+    let caller = ic_cdk::caller();
+    let mut refund = PENDING_REFUNDS.with(|refunds| refunds.borrow().get(&refund_id))
+        .ok_or_else(|| "Pending refund not found".to_string())?;
+    if refund.student != caller && !ic_cdk::api::is_controller(&caller) {
+        return Err("Only the student or a controller can retry this refund".to_string());
+    }
+    let token_canister = get_payment_config().token_canister.ok_or("Token canister is not configured")?;
+    // Claim the refund for the duration of the call so it is not sent twice.
+    if PENDING_REFUNDS.with(|refunds| refunds.borrow_mut().remove(&refund_id)).is_none() {
+        return Err("Pending refund not found".to_string());
+    }
+
+    match send_refund(token_canister, &refund).await {
+        Ok(()) => Ok(refund),
+        Err(error) => {
+            // Past the ledger's deduplication window the old timestamp is
+            // rejected outright, so the next attempt is issued afresh.
+            if error == RefundError::TooOld {
+                refund.created_at_time = time();
+            }
+            refund.last_error = error.to_string();
+            PENDING_REFUNDS.with(|refunds| refunds.borrow_mut().insert(refund_id, refund.clone()));
+            Err(format!("Refund failed: {}", refund.last_error))
+        }
+    }
+}
+
+#[derive(PartialEq)]
+enum RefundError {
+    TooOld,
+    Failed(String),
+}
+
+impl std::fmt::Display for RefundError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RefundError::TooOld => write!(f, "the refund is older than the ledger's deduplication window"),
+            RefundError::Failed(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+// A duplicate means an earlier attempt already reached the ledger.
+async fn send_refund(token_canister: Principal, refund: &PendingRefund) -> Result<(), RefundError> {
+    let refund_args = TransferArg {
+        from_subaccount: None,
+        to: Account { owner: refund.student, subaccount: None },
+        amount: Nat::from(refund.amount),
+        fee: None,
+        memo: None,
+        created_at_time: Some(refund.created_at_time),
+    };
+    let result: Result<(Result<Nat, TransferError>,), _> =
+        ic_cdk::call(token_canister, "icrc1_transfer", (refund_args,)).await;
+    match result {
+        Ok((Ok(_),)) | Ok((Err(TransferError::Duplicate { .. }),)) => Ok(()),
+        Ok((Err(TransferError::TooOld),)) => Err(RefundError::TooOld),
+        Ok((Err(err),)) => Err(RefundError::Failed(format!("{:?}", err))),
+        Err((code, message)) => Err(RefundError::Failed(format!("{:?} {}", code, message))),
+    }
+}
+
+// Splits a course price into the educator's and the treasury's payouts. The
+// treasury's share covers the fees of both payout transfers.
+fn split_payment(price: u64, educator_share_bps: u64, fee: u64) -> Result<(u64, u64), String> {
+    let educator_amount = (price as u128 * educator_share_bps as u128 / 10_000) as u64;
+    let treasury_amount = (price - educator_amount)
+        .checked_sub(fee.saturating_mul(2))
+        .ok_or("Course price does not cover the payout fees")?;
+    Ok((educator_amount, treasury_amount))
+}
+
+// Charges the student for the course, if it has a price. The full price is
+// first moved to this canister, then split between the educator and the
+// treasury in one batch so neither is paid without the other. If the payout
+// fails the student is refunded, less the refund's fee, and a refund that
+// fails too is kept for `retry_refund`.
+async fn pay_for_course(student: Principal, course_id: &str) -> Result<Option<CoursePayment>, String> {
+    let config = get_payment_config();
+    // Prices live in the course canister; without one every course is free,
+    // as it was before courses had prices.
+    let Some(course_canister) = config.course_canister else {
+        return Ok(None);
+    };
+
+    let (course,): (Result<CourseListing, String>,) =
+        ic_cdk::call(course_canister, "get_course", (course_id.to_string(),))
+            .await
+            .map_err(|(code, message)| format!("Failed to look up course: {:?} {}", code, message))?;
+    let course = course?;
+    let price = course.price.unwrap_or(0);
+    if price == 0 {
+        return Ok(None);
+    }
+
+    let token_canister = config.token_canister.ok_or("Token canister is not configured")?;
+    let treasury = config.treasury.ok_or("Treasury is not configured")?;
+
+    let (fee,): (Nat,) = ic_cdk::call(token_canister, "icrc1_fee", ())
+        .await
+        .map_err(|(code, message)| format!("Failed to fetch transfer fee: {:?} {}", code, message))?;
+    let fee = u64::try_from(&fee.0).map_err(|_| "Transfer fee does not fit in 64 bits".to_string())?;
+
+    let (educator_amount, treasury_amount) = split_payment(price, config.educator_share_bps, fee)?;
+
+    let payment_args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account { owner: student, subaccount: None },
+        to: Account { owner: ic_cdk::id(), subaccount: None },
+        amount: Nat::from(price),
+        fee: None,
+        memo: None,
+        created_at_time: Some(time()),
+    };
+    let (payment,): (Result<Nat, TransferFromError>,) =
+        ic_cdk::call(token_canister, "icrc2_transfer_from", (payment_args,))
+            .await
+            .map_err(|(code, message)| format!("Failed to charge for the course: {:?} {}", code, message))?;
+    let payment_block = payment.map_err(|err| format!("Payment failed: {:?}", err))?;
+
+    // The payment went through, so from here on every failure refunds it.
+    let payout_error = match u64::try_from(&payment_block.0) {
+        Ok(payment_block) => {
+            let memo = memo_for("Enrollment", &course.id);
+            let payouts = vec![
+                (course.educator_id, educator_amount, memo.clone()),
+                (treasury, treasury_amount, memo),
+            ];
+            let payout: Result<(Result<BatchTransferResult, TokenError>,), _> =
+                ic_cdk::call(token_canister, "batch_transfer", (payouts,)).await;
+            match payout {
+                Ok((Ok(result),)) => {
+                    return Ok(Some(CoursePayment {
+                        amount: price,
+                        educator_amount,
+                        treasury_amount,
+                        payment_block,
+                        payout_batch_id: result.batch_id,
+                    }));
+                }
+                Ok((Err(err),)) => format!("{:?}", err),
+                Err((code, message)) => format!("{:?} {}", code, message),
+            }
+        }
+        Err(_) => format!("Payment block index {} does not fit in 64 bits", payment_block),
+    };
+
+    let mut refund = PendingRefund {
+        id: 0,
+        student,
+        course_id: course.id,
+        amount: price.saturating_sub(fee),
+        created_at_time: time(),
+        last_error: String::new(),
+    };
+    match send_refund(token_canister, &refund).await {
+        Ok(()) => Err(format!("Course payout failed and the payment was refunded: {}", payout_error)),
+        Err(error) => {
+            refund.last_error = error.to_string();
+            let refund_id = PENDING_REFUNDS.with(|refunds| {
+                let mut refunds = refunds.borrow_mut();
+                refund.id = refunds.last_key_value().map(|(id, _)| id + 1).unwrap_or(0);
+                refunds.insert(refund.id, refund.clone());
+                refund.id
+            });
+            Err(format!(
+                "Course payout failed ({}) and so did the refund ({}); it is kept as pending refund {}",
+                payout_error, refund.last_error, refund_id
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_payment_gives_the_treasury_the_remainder_less_fees() {
+        assert_eq!(split_payment(1_000, 8_000, 10), Ok((800, 180)));
+        assert_eq!(split_payment(1_000, 10_000, 0), Ok((1_000, 0)));
+        assert_eq!(split_payment(999, 3_333, 0), Ok((332, 667)));
+    }
+
+    #[test]
+    fn enrollment_memos_fit_the_ledger_limit() {
+        let legacy_id = "rrkah-fqaaa-aaaaa-aaaaq-cai_1718000000000000000";
+        let salted_id = "course_18446744073709551615_9f86d081";
+        for course_id in [legacy_id, salted_id] {
+            let memo = memo_for("Enrollment", course_id);
+            assert!(memo.len() <= scholar_common::MAX_MEMO_LENGTH, "{} is too long", memo);
+        }
+        assert_ne!(memo_for("Enrollment", legacy_id), memo_for("Enrollment", salted_id));
+    }
+
+    #[test]
+    fn split_payment_rejects_prices_that_do_not_cover_the_fees() {
+        assert!(split_payment(1_000, 8_000, 101).is_err());
+        assert!(split_payment(1_000, 10_000, 1).is_err());
+    }
+}
@@ -1,17 +1,106 @@
-use candid::{CandidType, Deserialize, Principal};
-use ic_cdk::api::time;
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::{data_certificate, set_certified_data, time};
 use ic_cdk_macros::*;
+use ic_certification::{fork, label, leaf, HashTree};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::storable::{Bound, Storable};
 
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell};
+use scholar_common::{
+    Account, AllowanceArgs, AllowanceResponse, ApproveArgs, ApproveError, ArchivedBlock, ArchivedBlocks,
+    BatchTransferResult, Block, BlockWithId, CourseStake, FreezeReason, GetBlocksArgs, GetBlocksCallback, GetBlocksResult, Hash,
+    HoldStatus, IdAllocator, raw_rand_salt, MetadataChange, Subaccount, MAX_MEMO_LENGTH, TokenError, TokenTransaction, TransactionType, TransferArg, TransferError,
+    TransferFromArgs, TransferFromError,
+};
 
 use serde::Serialize;
+use sha2::{Digest, Sha256};
 use std::borrow::Cow;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::ops::Bound::{Excluded, Unbounded};
+use std::time::Duration;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
+// Metadata for ledgers installed without any, and for those that predate
+// stored metadata.
+const DEFAULT_TOKEN_NAME: &str = "ICP Scholar Token";
+const DEFAULT_TOKEN_SYMBOL: &str = "SCHOLAR";
+// Rewards and tips have always been whole-token amounts.
+const DEFAULT_TOKEN_DECIMALS: u8 = 0;
+const MAX_TOKEN_NAME_LENGTH: usize = 64;
+const MAX_TOKEN_SYMBOL_LENGTH: usize = 16;
+const MAX_TOKEN_DESCRIPTION_LENGTH: usize = 1024;
+// Logos are data URLs, e.g. a small SVG or PNG.
+const MAX_TOKEN_LOGO_LENGTH: usize = 32 * 1024;
+const DEFAULT_TRANSFER_FEE: u64 = 0;
+const MIN_BURN_AMOUNT: u64 = 1;
+// How long a request carrying `created_at_time` is remembered for
+// deduplication, and how far ahead of ledger time it may be dated.
+const DEFAULT_DEDUP_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const PERMITTED_DRIFT_NANOS: u64 = 2 * 60 * 1_000_000_000;
+const MAX_DEDUP_PRUNE: usize = 100;
+const MAX_BLOCKS_PER_REQUEST: u64 = 100;
+const DEFAULT_HISTORY_PAGE_SIZE: u64 = 50;
+const MAX_HISTORY_PAGE_SIZE: u64 = 500;
+// Upper bound on index entries examined per history page, so a selective
+// filter over a long history cannot exhaust the query's instruction limit.
+const MAX_HISTORY_SCAN: u64 = 5_000;
+// Longest refund window a hold may have, and how many expired holds one
+// timer tick releases before rescheduling itself.
+const MAX_HOLD_WINDOW_NANOS: u64 = 90 * 24 * 60 * 60 * 1_000_000_000;
+const MAX_HOLD_RELEASES: usize = 50;
+const MAX_HOLD_MEMO_LENGTH: usize = 256;
+// Subaccount of the ledger canister that holds escrowed tokens. The default
+// subaccount is the minting account, so escrow needs its own.
+const ESCROW_SUBACCOUNT: Subaccount = [1; 32];
+// Unvested reward tokens live here until the release timer pays them out.
+const VESTING_SUBACCOUNT: Subaccount = [2; 32];
+const DEFAULT_VESTING_THRESHOLD: u64 = 100;
+const DEFAULT_VESTING_CLIFF_NANOS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+const DEFAULT_VESTING_DURATION_NANOS: u64 = 90 * 24 * 60 * 60 * 1_000_000_000;
+const VESTING_RELEASE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MAX_VESTING_RELEASES: usize = 100;
+// Staked and unbonding tokens, and curator rewards not yet paid out.
+const STAKING_SUBACCOUNT: Subaccount = [3; 32];
+const CURATOR_POOL_SUBACCOUNT: Subaccount = [4; 32];
+const DEFAULT_UNBONDING_NANOS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+// Share of each course completion reward minted for the course's stakers,
+// in basis points. This is new supply on top of the reward, so it is off
+// until a controller sets it with `set_staking_config`.
+const DEFAULT_CURATOR_SHARE_BPS: u64 = 0;
+const STAKING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MAX_UNBONDING_RELEASES: usize = 100;
+const MAX_REWARD_COURSES: usize = 10;
+const MAX_CURATOR_PAYOUTS: usize = 100;
+const COURSE_REWARD_MEMO_PREFIX: &str = "Course completion reward: ";
+const MAX_COURSE_ID_LENGTH: usize = 64;
+// Mint caps apply over a rolling 24 hours, tracked in hourly buckets.
+const DEFAULT_MINTER_DAILY_CAP: u64 = 10_000;
+const DEFAULT_GLOBAL_DAILY_CAP: u64 = 100_000;
+const MINT_BUCKET_NANOS: u64 = 60 * 60 * 1_000_000_000;
+const MINT_WINDOW_BUCKETS: u64 = 24;
+const MAX_SNAPSHOT_LABEL_LENGTH: usize = 128;
+// Instructions an audit step may use before yielding to the next timer,
+// well below the per-message limit.
+const AUDIT_INSTRUCTION_BUDGET: u64 = 2_000_000_000;
+// Only the first issues of each kind are kept in the report; the counts
+// cover all of them.
+const MAX_AUDIT_FINDINGS: usize = 100;
+const AUDIT_PAGE_SIZE: usize = 500;
+const MAX_FREEZE_NOTE_LENGTH: usize = 256;
+const MAX_BATCH_TRANSFER_LEGS: usize = 500;
+// Once more than `trigger_threshold` blocks are held locally, the oldest
+// are sent to the archive canister, at most MAX_ARCHIVE_BATCH per call.
+const DEFAULT_ARCHIVE_TRIGGER_THRESHOLD: u64 = 2_000;
+const DEFAULT_NUM_BLOCKS_TO_ARCHIVE: u64 = 1_000;
+const MAX_ARCHIVE_BATCH: u64 = 250;
+// Rows per statement page served over HTTP.
+const DEFAULT_STATEMENT_PAGE_SIZE: u64 = 500;
+const MAX_STATEMENT_PAGE_SIZE: u64 = 2_000;
+const ARCHIVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 pub struct TokenBalance {
     pub owner: Principal,
@@ -19,25 +108,517 @@ pub struct TokenBalance {
     pub updated_at: u64,
 }
 
+// Everything that identifies a request for deduplication. Two requests
+// with the same key inside the window are the same request retried.
+#[derive(CandidType, Serialize)]
+struct DedupKey<'a> {
+    caller: Principal,
+    transaction_type: &'a TransactionType,
+    from: Option<Account>,
+    to: Option<Account>,
+    amount: u64,
+    fee: Option<u64>,
+    memo: &'a [u8],
+    created_at_time: u64,
+}
+
+enum DedupError {
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u64 },
+}
+
+impl From<DedupError> for TokenError {
+    fn from(err: DedupError) -> Self {
+        match err {
+            DedupError::TooOld => TokenError::TooOld,
+            DedupError::CreatedInFuture { ledger_time } => TokenError::CreatedInFuture { ledger_time },
+            DedupError::Duplicate { duplicate_of } => TokenError::Duplicate { duplicate_of },
+        }
+    }
+}
+
+impl From<DedupError> for TransferError {
+    fn from(err: DedupError) -> Self {
+        match err {
+            DedupError::TooOld => TransferError::TooOld,
+            DedupError::CreatedInFuture { ledger_time } => TransferError::CreatedInFuture { ledger_time },
+            DedupError::Duplicate { duplicate_of } => TransferError::Duplicate {
+                duplicate_of: Nat::from(duplicate_of),
+            },
+        }
+    }
+}
+
+impl From<DedupError> for ApproveError {
+    fn from(err: DedupError) -> Self {
+        match err {
+            DedupError::TooOld => ApproveError::TooOld,
+            DedupError::CreatedInFuture { ledger_time } => ApproveError::CreatedInFuture { ledger_time },
+            DedupError::Duplicate { duplicate_of } => ApproveError::Duplicate {
+                duplicate_of: Nat::from(duplicate_of),
+            },
+        }
+    }
+}
+
+impl From<DedupError> for TransferFromError {
+    fn from(err: DedupError) -> Self {
+        match err {
+            DedupError::TooOld => TransferFromError::TooOld,
+            DedupError::CreatedInFuture { ledger_time } => TransferFromError::CreatedInFuture { ledger_time },
+            DedupError::Duplicate { duplicate_of } => TransferFromError::Duplicate {
+                duplicate_of: Nat::from(duplicate_of),
+            },
+        }
+    }
+}
+
+// Counters and configuration that must survive upgrades. New fields should
+// be optional so state written by older versions still decodes.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct LedgerState {
+    pub total_supply: u64,
+    pub tip_before_upgrade: Option<(u64, Hash)>,
+    pub last_upgrade_check: Option<UpgradeCheck>,
+    pub transfer_fee: Option<u64>,
+    // Fees are burned when no collector is configured.
+    pub fee_collector: Option<Account>,
+    pub dedup_window_nanos: Option<u64>,
+    pub vesting_policy: Option<VestingPolicy>,
+    pub staking_config: Option<StakingConfig>,
+    pub mint_caps: Option<MintCaps>,
+    pub archive_config: Option<ArchiveConfig>,
+    pub reward_sources: Option<RewardSources>,
+    pub reward_claims_backfilled: Option<bool>,
+}
+
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
-pub struct TokenTransaction {
-    pub id: String,
-    pub from: Principal,
-    pub to: Principal,
+pub struct TokenMetadata {
+    pub name: String,
+    pub symbol: String,
+    // Fixed at install: changing it would rescale every balance.
+    pub decimals: u8,
+    // Data URL, e.g. "data:image/svg+xml;base64,...".
+    pub logo: Option<String>,
+    pub description: Option<String>,
+}
+
+impl Default for TokenMetadata {
+    fn default() -> Self {
+        TokenMetadata {
+            name: DEFAULT_TOKEN_NAME.to_string(),
+            symbol: DEFAULT_TOKEN_SYMBOL.to_string(),
+            decimals: DEFAULT_TOKEN_DECIMALS,
+            logo: None,
+            description: None,
+        }
+    }
+}
+
+// Fields left out stay as they are; an empty logo or description clears it.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TokenMetadataUpdate {
+    pub name: Option<String>,
+    pub symbol: Option<String>,
+    pub logo: Option<String>,
+    pub description: Option<String>,
+}
+
+// Canisters that completion rewards are checked against.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct RewardSources {
+    pub student_canister: Principal,
+    pub course_canister: Principal,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct RewardClaim {
+    pub student: Principal,
+    pub course_id: String,
     pub amount: u64,
-    pub transaction_type: TransactionType,
+    pub minter: Principal,
+    pub block_index: u64,
+    pub claimed_at: u64,
+}
+
+// The parts of student_canister's Certificate and course_canister's Course
+// that rewards are checked against.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CompletionCertificate {
+    pub student_id: Principal,
+    pub course_id: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CourseRewardInfo {
+    pub id: String,
+    pub token_reward: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ArchiveConfig {
+    // Blocks are only archived once a canister is configured.
+    pub archive_canister: Option<Principal>,
+    pub trigger_threshold: u64,
+    pub num_blocks_to_archive: u64,
+}
+
+// Blocks `start..=end` live in `canister_id`.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ArchiveRange {
+    pub canister_id: Principal,
+    pub start: u64,
+    pub end: u64,
+}
+
+// Daily limits on newly minted tokens. Individual minters can be given a
+// different cap with `set_minter_daily_cap`.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct MintCaps {
+    pub minter_daily_cap: u64,
+    pub global_daily_cap: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct BalanceCheckpoint {
+    pub balance: u64,
+    pub changed_at: u64,
+}
+
+// A named point in time that balances can be looked up at, e.g. the start
+// of a vote.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Snapshot {
+    pub id: u64,
+    pub label: String,
+    pub timestamp: u64,
+    pub created_by: Principal,
+    pub total_supply: u64,
+    // Number of blocks in the log when the snapshot was taken.
+    pub log_length: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct FreezeRecord {
+    pub principal: Principal,
+    pub reason: FreezeReason,
+    pub note: String,
+    pub frozen_by: Principal,
+    pub frozen_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FreezeAction {
+    Freeze,
+    Unfreeze,
+}
+
+// One entry in the freeze audit trail.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct FreezeEvent {
+    pub id: u64,
+    pub principal: Principal,
+    pub action: FreezeAction,
+    pub reason: FreezeReason,
+    pub note: String,
+    pub admin: Principal,
     pub timestamp: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditStatus {
+    Running,
+    Passed,
+    Failed,
+    // Interrupted by an upgrade.
+    Aborted,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct AccountDiscrepancy {
+    pub account: Account,
+    pub stored_balance: u64,
+    pub replayed_balance: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct BlockIssue {
+    pub block_index: u64,
+    pub issue: String,
+}
+
+// Result of replaying the block log and comparing it with BALANCES and the
+// stored total supply.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct AuditReport {
+    pub id: u64,
+    pub started_by: Principal,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub status: AuditStatus,
+    pub blocks_replayed: u64,
+    pub accounts_checked: u64,
+    pub stored_supply: u64,
+    pub replayed_supply: u64,
+    pub discrepancy_count: u64,
+    pub discrepancies: Vec<AccountDiscrepancy>,
+    pub block_issue_count: u64,
+    pub block_issues: Vec<BlockIssue>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MintQuota {
+    pub minter: Principal,
+    pub minter_daily_cap: u64,
+    pub minter_minted: u64,
+    pub minter_remaining: u64,
+    pub global_daily_cap: u64,
+    pub global_minted: u64,
+    pub global_remaining: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct StakingConfig {
+    pub unbonding_nanos: u64,
+    pub curator_share_bps: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Stake {
+    pub course_id: String,
+    pub staker: Account,
+    pub amount: u64,
+    pub rewards_earned: u64,
+    pub staked_at: u64,
+    pub updated_at: u64,
+    // Curator rewards kept in the pool while the staker is frozen.
+    pub withheld_rewards: Option<u64>,
+}
+
+// Tokens taken out of a stake, paid back once `available_at` has passed.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Unbonding {
+    pub id: u64,
+    pub course_id: String,
+    pub staker: Account,
+    pub amount: u64,
+    pub started_at: u64,
+    pub available_at: u64,
+}
+
+// Stakes are keyed by course first so a course's stakers are contiguous.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct StakeKey {
+    course_id: String,
+    staker: Account,
+}
+
+// Course rewards of at least `threshold` tokens vest: nothing is spendable
+// before the cliff, then the grant releases linearly until `duration_nanos`
+// after the reward.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct VestingPolicy {
+    pub threshold: u64,
+    pub cliff_nanos: u64,
+    pub duration_nanos: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct VestingGrant {
+    pub id: u64,
+    pub beneficiary: Account,
+    pub total: u64,
+    pub released: u64,
+    pub start: u64,
+    pub cliff_at: u64,
+    pub end: u64,
+    pub memo: String,
+    pub created_in_block: u64,
+}
+
+impl VestingGrant {
+    fn vested(&self, now: u64) -> u64 {
+        if now < self.cliff_at {
+            0
+        } else if now >= self.end {
+            self.total
+        } else {
+            let elapsed = (now - self.start) as u128;
+            let duration = (self.end - self.start) as u128;
+            (self.total as u128 * elapsed / duration) as u64
+        }
+    }
+
+    fn locked(&self) -> u64 {
+        self.total - self.released
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BalanceBreakdown {
+    pub spendable: u64,
+    pub locked: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FeeConfig {
+    pub transfer_fee: u64,
+    pub fee_collector: Option<Account>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct UpgradeCheck {
+    pub checked_at: u64,
+    pub stored_supply: u64,
+    pub recomputed_supply: u64,
+    pub tip_intact: bool,
+}
+
+// Tokens taken from `payer` and parked in escrow until they are released to
+// `payee` or refunded. Holds still active at `expires_at` are released.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Hold {
+    pub id: u64,
+    pub payer: Account,
+    pub payee: Account,
+    pub amount: u64,
+    // The caller that created the hold: the payer, or a spender using the
+    // payer's allowance.
+    pub created_by: Principal,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub memo: String,
+    pub status: HoldStatus,
+    pub created_in_block: u64,
+    pub resolved_at: Option<u64>,
+    pub resolved_in_block: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CreateHoldArgs {
+    // Defaults to the caller. Anyone else needs an allowance covering the
+    // amount and the fee.
+    pub from: Option<Account>,
+    pub payee: Account,
+    pub amount: u64,
+    pub refund_window_nanos: u64,
     pub memo: String,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
-pub enum TransactionType {
-    Mint,
-    Transfer,
-    CourseReward,
-    PeerTip,
+pub struct Allowance {
+    pub allowance: u64,
+    pub expires_at: Option<u64>,
+}
+
+impl Allowance {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransactionPage {
+    pub transactions: Vec<TokenTransaction>,
+    // Matching blocks that have moved to an archive, in block order.
+    pub archived: Vec<ArchivedTransactions>,
+    // Block index to pass as `start` for the next page, if there is one.
+    pub next: Option<u64>,
+}
+
+// Fetch these with the archive's `get_transactions`, passing the same
+// transaction type filter.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ArchivedTransactions {
+    pub canister_id: Principal,
+    pub block_indices: Vec<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+// One row of an account statement, seen from the statement's account.
+#[derive(Serialize, Clone, Debug)]
+pub struct StatementEntry {
+    pub block_index: u64,
+    pub transaction_id: String,
+    pub timestamp: u64,
+    pub kind: String,
+    // "in", "out" or "self".
+    pub direction: String,
+    pub counterparty: Principal,
+    pub counterparty_subaccount: Option<String>,
+    pub amount: u64,
+    // Only set when the account paid it.
+    pub fee: u64,
+    pub memo: String,
+    pub batch_id: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Statement {
+    pub account: Principal,
+    pub subaccount: Option<String>,
+    pub from: u64,
+    pub to: u64,
+    pub entries: Vec<StatementEntry>,
+    // Blocks in the range that moved to an archive; their timestamps are
+    // not known here, so they are listed without date filtering.
+    pub archived: Vec<ArchivedTransactions>,
+    // Pass as `start` for the next page.
+    pub next: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetArchivesArgs {
+    pub from: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ICRC3ArchiveInfo {
+    pub canister_id: Principal,
+    pub start: Nat,
+    pub end: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ICRC3DataCertificate {
+    pub certificate: Vec<u8>,
+    pub hash_tree: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SupportedBlockType {
+    pub block_type: String,
+    pub url: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum MetadataValue {
+    Nat(Nat),
+    Int(candid::Int),
+    Text(String),
+    Blob(Vec<u8>),
 }
 
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StandardRecord {
+    pub name: String,
+    pub url: String,
+}
 
 
 impl Storable for TokenBalance {
@@ -46,7 +627,7 @@ impl Storable for TokenBalance {
         is_fixed_size: false,
     };
 
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(candid::encode_one(self).unwrap())
     }
 
@@ -55,13 +636,13 @@ impl Storable for TokenBalance {
     }
 }
 
-impl Storable for TokenTransaction {
+impl Storable for Allowance {
     const BOUND: Bound = Bound::Bounded {
-        max_size: 512,
+        max_size: 128,
         is_fixed_size: false,
     };
 
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(candid::encode_one(self).unwrap())
     }
 
@@ -70,237 +651,4487 @@ impl Storable for TokenTransaction {
     }
 }
 
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
-    );
-
-    static BALANCES: RefCell<StableBTreeMap<Principal, TokenBalance, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
-        )
-    );
+impl Storable for Hold {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1024,
+        is_fixed_size: false,
+    };
 
-    static TRANSACTIONS: RefCell<StableBTreeMap<String, TokenTransaction, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
-        )
-    );
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
 
-    static TOTAL_SUPPLY: RefCell<u64> = RefCell::new(0);
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
 }
 
-#[update]
-pub fn mint_tokens(to: Principal, amount: u64, memo: String) -> Result<TokenTransaction, String> {
-    let caller = ic_cdk::caller();
-    let now = time();
-    let transaction_id = format!("{}_{}", now, caller.to_text());
-    
-    // Update balance
-    BALANCES.with(|balances| {
-        let mut balances_map = balances.borrow_mut();
-        let mut balance = balances_map.get(&to).unwrap_or(TokenBalance {
-            owner: to,
-            balance: 0,
-            updated_at: now,
-        });
-        
-        balance.balance += amount;
-        balance.updated_at = now;
-        balances_map.insert(to, balance);
-    });
-    
-    // Update total supply
-    TOTAL_SUPPLY.with(|supply| {
-        *supply.borrow_mut() += amount;
-    });
-    
-    // Record transaction
-    let transaction = TokenTransaction {
-        id: transaction_id.clone(),
-        from: caller,
-        to,
-        amount,
-        transaction_type: TransactionType::Mint,
-        timestamp: now,
-        memo,
+impl Storable for VestingGrant {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1024,
+        is_fixed_size: false,
     };
-    
-    TRANSACTIONS.with(|transactions| {
-        transactions.borrow_mut().insert(transaction_id, transaction.clone());
-    });
-    
-    Ok(transaction)
-}
 
-#[update]
-pub fn transfer_tokens(to: Principal, amount: u64, memo: String) -> Result<TokenTransaction, String> {
-    let caller = ic_cdk::caller();
-    let now = time();
-    let transaction_id = format!("{}_{}", now, caller.to_text());
-    
-    // Check sender balance
-    let sender_balance = BALANCES.with(|balances| {
-        balances.borrow().get(&caller).map(|b| b.balance).unwrap_or(0)
-    });
-    
-    if sender_balance < amount {
-        return Err("Insufficient balance".to_string());
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
     }
-    
-    // Update balances
-    BALANCES.with(|balances| {
-        let mut balances_map = balances.borrow_mut();
-        
-        // Deduct from sender
-        let mut sender_balance = balances_map.get(&caller).unwrap();
-        sender_balance.balance -= amount;
-        sender_balance.updated_at = now;
-        balances_map.insert(caller, sender_balance);
-        
-        // Add to recipient
-        let mut recipient_balance = balances_map.get(&to).unwrap_or(TokenBalance {
-            owner: to,
-            balance: 0,
-            updated_at: now,
-        });
-        recipient_balance.balance += amount;
-        recipient_balance.updated_at = now;
-        balances_map.insert(to, recipient_balance);
-    });
-    
-    // Record transaction
-    let transaction = TokenTransaction {
-        id: transaction_id.clone(),
-        from: caller,
-        to,
-        amount,
-        transaction_type: TransactionType::Transfer,
-        timestamp: now,
-        memo,
-    };
-    
-    TRANSACTIONS.with(|transactions| {
-        transactions.borrow_mut().insert(transaction_id, transaction.clone());
-    });
-    
-    Ok(transaction)
 }
 
-#[update]
-pub fn reward_course_completion(student: Principal, amount: u64, course_id: String) -> Result<TokenTransaction, String> {
-    let caller = ic_cdk::caller();
-    let now = time();
-    let transaction_id = format!("{}_{}", now, caller.to_text());
-    
-    // Update balance
-    BALANCES.with(|balances| {
-        let mut balances_map = balances.borrow_mut();
-        let mut balance = balances_map.get(&student).unwrap_or(TokenBalance {
-            owner: student,
-            balance: 0,
-            updated_at: now,
-        });
-        
-        balance.balance += amount;
-        balance.updated_at = now;
-        balances_map.insert(student, balance);
-    });
-    
-    // Record transaction
-    let transaction = TokenTransaction {
-        id: transaction_id.clone(),
-        from: caller,
-        to: student,
-        amount,
-        transaction_type: TransactionType::CourseReward,
-        timestamp: now,
-        memo: format!("Course completion reward: {}", course_id),
+impl Storable for StakeKey {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1 + MAX_COURSE_ID_LENGTH as u32 + 29 + 32,
+        is_fixed_size: false,
     };
-    
-    TRANSACTIONS.with(|transactions| {
-        transactions.borrow_mut().insert(transaction_id, transaction.clone());
-    });
-    
-    Ok(transaction)
-}
 
-#[update]
-pub fn tip_peer(to: Principal, amount: u64, memo: String) -> Result<TokenTransaction, String> {
-    let caller = ic_cdk::caller();
-    let now = time();
-    let transaction_id = format!("{}_{}", now, caller.to_text());
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = vec![self.course_id.len() as u8];
+        bytes.extend_from_slice(self.course_id.as_bytes());
+        bytes.extend_from_slice(&self.staker.to_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (course_id, staker) = bytes[1..].split_at(bytes[0] as usize);
+        StakeKey {
+            course_id: String::from_utf8(course_id.to_vec()).unwrap(),
+            staker: Account::from_bytes(Cow::Borrowed(staker)),
+        }
+    }
+}
+
+impl Storable for Stake {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 256,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+impl Storable for Unbonding {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 256,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+impl Storable for BalanceCheckpoint {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 64,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+impl Storable for Snapshot {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 512,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+impl Storable for AuditReport {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 64 * 1024,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+impl Storable for FreezeRecord {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 512,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+impl Storable for FreezeEvent {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 512,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+impl Storable for TokenMetadata {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: (MAX_TOKEN_LOGO_LENGTH + MAX_TOKEN_DESCRIPTION_LENGTH + 512) as u32,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+impl Storable for RewardClaim {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 256,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+impl Storable for ArchiveRange {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 128,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+impl Storable for LedgerState {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1024,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    static BALANCES: RefCell<StableBTreeMap<Account, TokenBalance, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
+        )
+    );
+
+    // String-keyed transactions written before the block log existed;
+    // drained into BLOCKS on upgrade.
+    static LEGACY_TRANSACTIONS: RefCell<StableBTreeMap<String, TokenTransaction, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
+        )
+    );
+
+    // Keyed by (owner, spender).
+    static ALLOWANCES: RefCell<StableBTreeMap<(Account, Account), Allowance, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
+        )
+    );
+
+    static BLOCKS: RefCell<StableBTreeMap<u64, Block, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
+        )
+    );
+
+    // Principals allowed to mint, with the time they were added.
+    static MINTERS: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+        )
+    );
+
+    static STATE: RefCell<StableCell<LedgerState, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))),
+            LedgerState::default(),
+        ).expect("failed to initialize ledger state")
+    );
+
+    // (account, block index) for every block touching the account.
+    static ACCOUNT_TRANSACTIONS: RefCell<StableBTreeMap<(Account, u64), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))),
+        )
+    );
+
+    // Dedup key hash -> block index of the transaction it produced.
+    static RECENT_TRANSACTIONS: RefCell<StableBTreeMap<Hash, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))),
+        )
+    );
+
+    // (created_at_time, dedup key hash), used to expire RECENT_TRANSACTIONS.
+    static RECENT_TRANSACTIONS_BY_TIME: RefCell<StableBTreeMap<(u64, Hash), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
+        )
+    );
+
+    static ID_COUNTERS: RefCell<IdAllocator<Memory>> = RefCell::new(
+        IdAllocator::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))),
+    );
+
+    static HOLDS: RefCell<StableBTreeMap<u64, Hold, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+        )
+    );
+
+    // (expires_at, hold id) for every active hold.
+    static HOLD_EXPIRIES: RefCell<StableBTreeMap<(u64, u64), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+        )
+    );
+
+    // Deadline and id of the pending hold expiry timer. Timers do not
+    // survive upgrades, so post_upgrade schedules it again.
+    static HOLD_TIMER: Cell<Option<(u64, ic_cdk_timers::TimerId)>> = const { Cell::new(None) };
+
+    static VESTING_GRANTS: RefCell<StableBTreeMap<u64, VestingGrant, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+        )
+    );
+
+    // Ids of grants with tokens still locked.
+    static ACTIVE_VESTING_GRANTS: RefCell<StableBTreeMap<u64, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
+        )
+    );
+
+    // Grant id the next release pass starts from, so every grant gets its
+    // turn when there are more than one pass handles.
+    static VESTING_CURSOR: Cell<u64> = const { Cell::new(0) };
+
+    static STAKES: RefCell<StableBTreeMap<StakeKey, Stake, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+        )
+    );
+
+    // Course id -> total staked on the course.
+    static COURSE_STAKE_TOTALS: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))),
+        )
+    );
+
+    // Keyed by (available_at, id) so matured unbondings come first.
+    static UNBONDINGS: RefCell<StableBTreeMap<(u64, u64), Unbonding, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))),
+        )
+    );
+
+    // Course id -> curator rewards waiting to be distributed.
+    static CURATOR_POOLS: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))),
+        )
+    );
+
+    // Minters with a cap other than the default.
+    static MINTER_DAILY_CAPS: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))),
+        )
+    );
+
+    // (minter, hour) -> tokens minted by the minter in that hour.
+    static MINTER_EMISSIONS: RefCell<StableBTreeMap<(Principal, u64), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+        )
+    );
+
+    // Hour -> tokens minted by anyone in that hour.
+    static GLOBAL_EMISSIONS: RefCell<StableBTreeMap<u64, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))),
+        )
+    );
+
+    // (account, time) -> balance, as recorded before checkpoints were keyed
+    // by block. Emptied into BALANCE_CHECKPOINTS on upgrade.
+    static LEGACY_BALANCE_CHECKPOINTS: RefCell<StableBTreeMap<(Account, u64), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))),
+        )
+    );
+
+    // (account, block index) -> balance once that block was applied.
+    static BALANCE_CHECKPOINTS: RefCell<StableBTreeMap<(Account, u64), BalanceCheckpoint, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))),
+        )
+    );
+
+    // Curator reward shares split off a course pool but not yet paid.
+    static CURATOR_PAYOUTS: RefCell<StableBTreeMap<StakeKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30))),
+        )
+    );
+
+    static SNAPSHOTS: RefCell<StableBTreeMap<u64, Snapshot, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))),
+        )
+    );
+
+    static AUDIT_REPORTS: RefCell<StableBTreeMap<u64, AuditReport, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))),
+        )
+    );
+
+    // Frozen principals. Freezing covers every subaccount of the principal.
+    static FROZEN_ACCOUNTS: RefCell<StableBTreeMap<Principal, FreezeRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))),
+        )
+    );
+
+    static FREEZE_EVENTS: RefCell<StableBTreeMap<u64, FreezeEvent, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))),
+        )
+    );
+
+    // Archived block ranges keyed by their first block.
+    static ARCHIVES: RefCell<StableBTreeMap<u64, ArchiveRange, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))),
+        )
+    );
+
+    // Paid completion rewards, keyed like student_canister's certificates
+    // ("{student}_{course_id}").
+    static REWARD_CLAIMS: RefCell<StableBTreeMap<String, RewardClaim, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))),
+        )
+    );
+
+    static METADATA: RefCell<StableCell<TokenMetadata, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))),
+            TokenMetadata::default(),
+        ).expect("failed to initialize token metadata")
+    );
+
+    // Set while a batch is on its way to the archive.
+    static ARCHIVING: Cell<bool> = const { Cell::new(false) };
+
+    // Progress of the running audit. An upgrade cancels its timer, so this
+    // does not need to survive one.
+    static AUDIT: RefCell<Option<AuditProgress>> = const { RefCell::new(None) };
+}
+
+#[update]
+pub fn mint_tokens(to: Principal, amount: u64, memo: String) -> Result<TokenTransaction, TokenError> {
+    let caller = ic_cdk::caller();
+    let now = time();
+    ensure_minter(caller)?;
+    ensure_not_frozen(to)?;
+    check_mint_quota(caller, amount, now)?;
+    
+    // Credit the recipient and grow the supply
+    mint(Account::new(to), amount, now)?;
+    record_emission(caller, amount, now);
+    
+    // Record transaction
+    let transaction = TokenTransaction {
+        id: next_id("tx"),
+        from: caller,
+        to,
+        amount,
+        transaction_type: TransactionType::Mint,
+        timestamp: now,
+        memo,
+        from_subaccount: None,
+        to_subaccount: None,
+        spender: None,
+        fee: None,
+        batch_id: None,
+        fee_collector: None,
+        metadata: None,
+        raw_memo: None,
+    };
+    
+    record_transaction(transaction.clone());
+    
+    Ok(transaction)
+}
+
+#[update]
+pub fn transfer_tokens(to: Principal, amount: u64, memo: String, created_at_time: Option<u64>) -> Result<TokenTransaction, TokenError> {
+    let caller = ic_cdk::caller();
+    let now = time();
+    let fee = transfer_fee();
+    ensure_not_frozen(caller)?;
+    check_memo(&memo)?;
+    
+    // Reject retries of a request that already went through
+    let dedup = deduplicate(created_at_time.map(|created_at_time| DedupKey {
+        caller,
+        transaction_type: &TransactionType::Transfer,
+        from: Some(Account::new(caller)),
+        to: Some(Account::new(to)),
+        amount,
+        fee: None,
+        memo: memo.as_bytes(),
+        created_at_time,
+    }), now)?;
+    
+    // Move the tokens and charge the fee to the sender
+    move_balance(Account::new(caller), Account::new(to), amount, fee, now)
+        .map_err(|balance| TokenError::InsufficientFunds { balance })?;
+    
+    // Record transaction
+    let transaction = TokenTransaction {
+        id: next_id("tx"),
+        from: caller,
+        to,
+        amount,
+        transaction_type: TransactionType::Transfer,
+        timestamp: now,
+        memo,
+        from_subaccount: None,
+        to_subaccount: None,
+        spender: None,
+        fee: Some(fee),
+        batch_id: None,
+        fee_collector: fee_collector_for(fee),
+        metadata: None,
+        raw_memo: None,
+    };
+    
+    let block_index = record_transaction(transaction.clone());
+    remember_transaction(dedup, block_index, now);
+    
+    Ok(transaction)
+}
+
+// Mints a course's token reward for a student holding its certificate, once
+// per student and course. The completion is checked with student_canister
+// and the amount read from course_canister rather than taken from the
+// caller.
+#[update]
+pub async fn reward_course_completion(student: Principal, course_id: String) -> Result<TokenTransaction, TokenError> {
+    let caller = ic_cdk::caller();
+    ensure_minter(caller)?;
+    ensure_not_frozen(student)?;
+    ensure_reward_unclaimed(student, &course_id)?;
+    let sources = read_state(|state| state.reward_sources.clone()).ok_or_else(|| TokenError::GenericError {
+        message: "Reward sources are not configured".to_string(),
+    })?;
+
+    let (certificate,): (Option<CompletionCertificate>,) =
+        ic_cdk::call(sources.student_canister, "get_certificate", (student, course_id.clone()))
+            .await
+            .map_err(|(code, message)| TokenError::GenericError {
+                message: format!("Failed to look up certificate: {:?} {}", code, message),
+            })?;
+    if !certificate.is_some_and(|certificate| certificate.student_id == student && certificate.course_id == course_id) {
+        return Err(TokenError::GenericError {
+            message: "Student has no certificate for this course".to_string(),
+        });
+    }
+
+    let (course,): (Result<CourseRewardInfo, String>,) =
+        ic_cdk::call(sources.course_canister, "get_course", (course_id.clone(),))
+            .await
+            .map_err(|(code, message)| TokenError::GenericError {
+                message: format!("Failed to look up course: {:?} {}", code, message),
+            })?;
+    let amount = course.map_err(|message| TokenError::GenericError { message })?.token_reward;
+    if amount == 0 {
+        return Err(TokenError::GenericError {
+            message: "Course has no token reward".to_string(),
+        });
+    }
+
+    pay_course_reward(caller, student, amount, course_id)
+}
+
+#[update]
+pub fn set_reward_sources(sources: RewardSources) -> Result<RewardSources, TokenError> {
+    ensure_controller(ic_cdk::caller())?;
+    mutate_state(|state| state.reward_sources = Some(sources.clone()));
+    Ok(sources)
+}
+
+#[query]
+pub fn get_reward_sources() -> Option<RewardSources> {
+    read_state(|state| state.reward_sources.clone())
+}
+
+#[query]
+pub fn get_reward_claim(student: Principal, course_id: String) -> Option<RewardClaim> {
+    REWARD_CLAIMS.with(|claims| claims.borrow().get(&reward_claim_key(student, &course_id)))
+}
+
+// Everything after the lookups in `reward_course_completion`. The checks
+// are repeated since the caller, the student or the claim may have changed
+// while it waited.
+fn pay_course_reward(caller: Principal, student: Principal, amount: u64, course_id: String) -> Result<TokenTransaction, TokenError> {
+    let now = time();
+    ensure_minter(caller)?;
+    ensure_not_frozen(student)?;
+    ensure_reward_unclaimed(student, &course_id)?;
+
+    // The reward and the curators' share are minted together, so both must
+    // fit before either is.
+    let curator_share = curator_share(&course_id, amount);
+    let minted = amount.checked_add(curator_share).ok_or(TokenError::Overflow)?;
+    check_mint_quota(caller, minted, now)?;
+    read_state(|state| state.total_supply)
+        .checked_add(minted)
+        .ok_or(TokenError::Overflow)?;
+    let memo = format!("{}{}", COURSE_REWARD_MEMO_PREFIX, course_id);
+    
+    // Large rewards are minted into the vesting account and paid out over
+    // time; smaller ones are spendable right away
+    let policy = vesting_policy();
+    let vests = amount >= policy.threshold;
+    let recipient = if vests { vesting_account() } else { Account::new(student) };
+    
+    // Rewards are minted, so they count towards the supply
+    mint(recipient, amount, now)?;
+    record_emission(caller, amount, now);
+    
+    // Record transaction
+    let transaction = TokenTransaction {
+        id: next_id("tx"),
+        from: caller,
+        to: recipient.owner,
+        amount,
+        transaction_type: TransactionType::CourseReward,
+        timestamp: now,
+        memo: memo.clone(),
+        from_subaccount: None,
+        to_subaccount: recipient.subaccount,
+        spender: None,
+        fee: None,
+        batch_id: None,
+        fee_collector: None,
+        metadata: None,
+        raw_memo: None,
+    };
+    
+    let block_index = record_transaction(transaction.clone());
+    REWARD_CLAIMS.with(|claims| {
+        claims.borrow_mut().insert(reward_claim_key(student, &course_id), RewardClaim {
+            student,
+            course_id: course_id.clone(),
+            amount,
+            minter: caller,
+            block_index,
+            claimed_at: now,
+        })
+    });
+    
+    if vests {
+        let grant = VestingGrant {
+            id: next_sequence("vesting"),
+            beneficiary: Account::new(student),
+            total: amount,
+            released: 0,
+            start: now,
+            cliff_at: now.saturating_add(policy.cliff_nanos),
+            end: now.saturating_add(policy.duration_nanos),
+            memo,
+            created_in_block: block_index,
+        };
+        ACTIVE_VESTING_GRANTS.with(|active| active.borrow_mut().insert(grant.id, ()));
+        VESTING_GRANTS.with(|grants| grants.borrow_mut().insert(grant.id, grant));
+    }
+    
+    fund_curator_pool(&course_id, curator_share, caller, now)?;
+    
+    Ok(transaction)
+}
+
+// Tips from the caller, or, for a canister the tipper approved as an
+// ICRC-2 spender (e.g. peer_canister tipping a note), from `from`.
+#[update]
+pub fn tip_peer(to: Principal, amount: u64, memo: String, created_at_time: Option<u64>, from: Option<Principal>) -> Result<TokenTransaction, TokenError> {
+    let caller = ic_cdk::caller();
+    let now = time();
+    let fee = transfer_fee();
+    let tipper = Account::new(from.unwrap_or(caller));
+    let spender = Account::new(caller);
+    ensure_not_frozen(tipper.owner)?;
+    check_memo(&memo)?;
+    
+    // Reject retries of a request that already went through
+    let dedup = deduplicate(created_at_time.map(|created_at_time| DedupKey {
+        caller,
+        transaction_type: &TransactionType::PeerTip,
+        from: Some(tipper),
+        to: Some(Account::new(to)),
+        amount,
+        fee: None,
+        memo: memo.as_bytes(),
+        created_at_time,
+    }), now)?;
+    
+    let debited = amount_with_fee(amount, fee)?;
+    let allowance = check_allowance(tipper, spender, debited, now)
+        .map_err(|allowance| TokenError::InsufficientAllowance { allowance })?;
+    
+    // Move the tokens and charge the fee to the tipper
+    move_balance(tipper, Account::new(to), amount, fee, now)
+        .map_err(|balance| TokenError::InsufficientFunds { balance })?;
+    use_allowance(tipper, spender, allowance, debited);
+    
+    // Record transaction
+    let transaction = TokenTransaction {
+        id: next_id("tx"),
+        from: tipper.owner,
+        to,
+        amount,
+        transaction_type: TransactionType::PeerTip,
+        timestamp: now,
+        memo,
+        from_subaccount: None,
+        to_subaccount: None,
+        spender: (tipper != spender).then_some(spender),
+        fee: Some(fee),
+        batch_id: None,
+        fee_collector: fee_collector_for(fee),
+        metadata: None,
+        raw_memo: None,
+    };
+    
+    let block_index = record_transaction(transaction.clone());
+    remember_transaction(dedup, block_index, now);
+    
+    Ok(transaction)
+}
+
+// Pays every leg or none: the sender's balance must cover all amounts and
+// fees before anything moves.
+#[update]
+pub fn batch_transfer(transfers: Vec<(Principal, u64, String)>) -> Result<BatchTransferResult, TokenError> {
+    let caller = ic_cdk::caller();
+    let now = time();
+    let fee = transfer_fee();
+    ensure_not_frozen(caller)?;
+
+    if transfers.is_empty() || transfers.len() > MAX_BATCH_TRANSFER_LEGS {
+        return Err(TokenError::GenericError {
+            message: format!("A batch must have 1 to {} transfers", MAX_BATCH_TRANSFER_LEGS),
+        });
+    }
+
+    let mut total_amount: u64 = 0;
+    let mut total_fee: u64 = 0;
+    for (_, amount, memo) in &transfers {
+        check_memo(memo)?;
+        total_amount = total_amount.checked_add(*amount).ok_or(TokenError::Overflow)?;
+        total_fee = total_fee.checked_add(fee).ok_or(TokenError::Overflow)?;
+    }
+    let total = amount_with_fee(total_amount, total_fee)?;
+    let balance = get_balance(Some(caller));
+    if balance < total {
+        return Err(TokenError::InsufficientFunds { balance });
+    }
+
+    let batch_id = next_id("batch");
+    let mut transactions = Vec::with_capacity(transfers.len());
+    for (to, amount, memo) in transfers {
+        move_balance(Account::new(caller), Account::new(to), amount, fee, now)
+            .expect("balance was checked to cover the whole batch");
+
+        let transaction = TokenTransaction {
+            id: next_id("tx"),
+            from: caller,
+            to,
+            amount,
+            transaction_type: TransactionType::Transfer,
+            timestamp: now,
+            memo,
+            from_subaccount: None,
+            to_subaccount: None,
+            spender: None,
+            fee: Some(fee),
+            batch_id: Some(batch_id.clone()),
+            fee_collector: fee_collector_for(fee),
+            metadata: None,
+            raw_memo: None,
+        };
+        record_transaction(transaction.clone());
+        transactions.push(transaction);
+    }
+
+    Ok(BatchTransferResult {
+        batch_id,
+        total_amount,
+        total_fee,
+        transactions,
+    })
+}
+
+#[query]
+pub fn get_balance(principal: Option<Principal>) -> u64 {
+    let target_principal = principal.unwrap_or(ic_cdk::caller());
     
-    // Check sender balance
-    let sender_balance = BALANCES.with(|balances| {
-        balances.borrow().get(&caller).map(|b| b.balance).unwrap_or(0)
+    BALANCES.with(|balances| {
+        balances.borrow().get(&Account::new(target_principal)).map(|b| b.balance).unwrap_or(0)
+    })
+}
+
+// Balance of the principal's default account at the end of `timestamp`.
+#[query]
+pub fn get_balance_at(principal: Principal, timestamp: u64) -> u64 {
+    balance_at(Account::new(principal), timestamp)
+}
+
+// Minters and controllers can take snapshots for features that need a
+// fixed set of balances, such as token-weighted votes.
+#[update]
+pub fn create_snapshot(label: String) -> Result<Snapshot, TokenError> {
+    let caller = ic_cdk::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        ensure_minter(caller)?;
+    }
+    if label.len() > MAX_SNAPSHOT_LABEL_LENGTH {
+        return Err(TokenError::GenericError {
+            message: format!("Label is longer than {} bytes", MAX_SNAPSHOT_LABEL_LENGTH),
+        });
+    }
+
+    let snapshot = Snapshot {
+        id: next_sequence("snapshot"),
+        label,
+        timestamp: time(),
+        created_by: caller,
+        total_supply: read_state(|state| state.total_supply),
+        log_length: last_block().map(|(index, _)| index + 1).unwrap_or(0),
+    };
+    SNAPSHOTS.with(|snapshots| snapshots.borrow_mut().insert(snapshot.id, snapshot.clone()));
+
+    Ok(snapshot)
+}
+
+#[query]
+pub fn get_snapshot(snapshot_id: u64) -> Option<Snapshot> {
+    SNAPSHOTS.with(|snapshots| snapshots.borrow().get(&snapshot_id))
+}
+
+#[query]
+pub fn get_snapshots() -> Vec<Snapshot> {
+    SNAPSHOTS.with(|snapshots| snapshots.borrow().iter().map(|(_, snapshot)| snapshot).collect())
+}
+
+#[query]
+pub fn get_balance_at_snapshot(snapshot_id: u64, principal: Principal) -> Result<u64, TokenError> {
+    let snapshot = get_snapshot(snapshot_id).ok_or(TokenError::SnapshotNotFound { snapshot_id })?;
+    Ok(match snapshot.log_length.checked_sub(1) {
+        Some(last_block) => balance_at_block(Account::new(principal), last_block),
+        None => 0,
+    })
+}
+
+#[query]
+pub fn get_transaction_history(
+    account: Option<Account>,
+    start: Option<u64>,
+    limit: Option<u64>,
+    transaction_type: Option<TransactionType>,
+) -> TransactionPage {
+    let account = account.unwrap_or(Account::new(ic_cdk::caller()));
+    let start = start.unwrap_or(0);
+    let limit = limit.unwrap_or(DEFAULT_HISTORY_PAGE_SIZE).min(MAX_HISTORY_PAGE_SIZE);
+
+    let first_local = first_local_block();
+    let mut transactions = Vec::new();
+    let mut archived: Vec<ArchivedTransactions> = Vec::new();
+    let mut next = None;
+    let mut scanned = 0;
+
+    ACCOUNT_TRANSACTIONS.with(|index| {
+        for ((_, block_index), _) in index.borrow().range((account, start)..=(account, u64::MAX)) {
+            let returned = transactions.len() + archived.iter().map(|a| a.block_indices.len()).sum::<usize>();
+            if returned as u64 >= limit || scanned >= MAX_HISTORY_SCAN {
+                next = Some(block_index);
+                break;
+            }
+            scanned += 1;
+
+            // The type filter is applied by the archive for these.
+            if block_index < first_local {
+                push_archived(&mut archived, block_index);
+                continue;
+            }
+
+            let Some(block) = BLOCKS.with(|blocks| blocks.borrow().get(&block_index)) else {
+                continue;
+            };
+            let matches = match &transaction_type {
+                Some(wanted) => *wanted == block.transaction.transaction_type,
+                None => true,
+            };
+            if matches {
+                transactions.push(block.transaction);
+            }
+        }
+    });
+
+    TransactionPage {
+        transactions,
+        archived,
+        next,
+    }
+}
+
+#[query]
+pub fn get_total_supply() -> u64 {
+    read_state(|state| state.total_supply)
+}
+
+#[update]
+pub fn add_minter(minter: Principal) -> Result<TokenTransaction, TokenError> {
+    let caller = ic_cdk::caller();
+    ensure_controller(caller)?;
+
+    let now = time();
+    let added = MINTERS.with(|minters| minters.borrow_mut().insert(minter, now).is_none());
+    if !added {
+        return Err(TokenError::GenericError {
+            message: "Principal is already a minter".to_string(),
+        });
+    }
+
+    Ok(record_minter_change(caller, minter, TransactionType::AddMinter, now))
+}
+
+#[update]
+pub fn remove_minter(minter: Principal) -> Result<TokenTransaction, TokenError> {
+    let caller = ic_cdk::caller();
+    ensure_controller(caller)?;
+
+    let now = time();
+    let removed = MINTERS.with(|minters| minters.borrow_mut().remove(&minter).is_some());
+    if !removed {
+        return Err(TokenError::GenericError {
+            message: "Principal is not a minter".to_string(),
+        });
+    }
+
+    Ok(record_minter_change(caller, minter, TransactionType::RemoveMinter, now))
+}
+
+#[query]
+pub fn get_minters() -> Vec<Principal> {
+    MINTERS.with(|minters| minters.borrow().iter().map(|(minter, _)| minter).collect())
+}
+
+#[update]
+pub fn set_mint_caps(caps: MintCaps) -> Result<MintCaps, TokenError> {
+    ensure_controller(ic_cdk::caller())?;
+    mutate_state(|state| state.mint_caps = Some(caps.clone()));
+    Ok(caps)
+}
+
+#[query]
+pub fn get_mint_caps() -> MintCaps {
+    mint_caps()
+}
+
+// Overrides the default daily cap for one minter; `None` restores the
+// default.
+#[update]
+pub fn set_minter_daily_cap(minter: Principal, cap: Option<u64>) -> Result<MintQuota, TokenError> {
+    ensure_controller(ic_cdk::caller())?;
+    MINTER_DAILY_CAPS.with(|caps| match cap {
+        Some(cap) => caps.borrow_mut().insert(minter, cap),
+        None => caps.borrow_mut().remove(&minter),
+    });
+    Ok(get_mint_quota(Some(minter)))
+}
+
+// What the minter, defaulting to the caller, can still mint in the current
+// window.
+#[query]
+pub fn get_mint_quota(minter: Option<Principal>) -> MintQuota {
+    let minter = minter.unwrap_or(ic_cdk::caller());
+    mint_quota(minter, time())
+}
+
+// Burns from the caller's account, or, for minters, from `from` up to the
+// allowance its owner approved for them (e.g. to redeem a certificate fee
+// on a student's behalf).
+#[update]
+pub fn burn_tokens(from: Option<Principal>, amount: u64, memo: String) -> Result<TokenTransaction, TokenError> {
+    let caller = ic_cdk::caller();
+    let now = time();
+    let owner = from.unwrap_or(caller);
+    ensure_not_frozen(owner)?;
+    check_memo(&memo)?;
+
+    // Minters burn on a holder's behalf only within what the holder approved.
+    let from = Account::new(owner);
+    let burner = (owner != caller).then(|| Account::new(caller));
+    let allowance = match burner {
+        Some(burner) => {
+            ensure_minter(caller)?;
+            check_allowance(from, burner, amount, now)
+                .map_err(|allowance| TokenError::InsufficientAllowance { allowance })?
+        }
+        None => None,
+    };
+
+    let (_, transaction) = burn(from, amount, memo.into_bytes(), burner, now)?;
+    if let Some(burner) = burner {
+        use_allowance(from, burner, allowance, amount);
+    }
+    Ok(transaction)
+}
+
+#[update]
+pub fn set_transfer_fee(fee: u64) -> Result<FeeConfig, TokenError> {
+    ensure_controller(ic_cdk::caller())?;
+    mutate_state(|state| state.transfer_fee = Some(fee));
+    Ok(get_fee_config())
+}
+
+#[update]
+pub fn set_fee_collector(fee_collector: Option<Account>) -> Result<FeeConfig, TokenError> {
+    let caller = ic_cdk::caller();
+    ensure_controller(caller)?;
+    mutate_state(|state| state.fee_collector = fee_collector);
+
+    // Logged so replaying the chain knows where fees went. Clearing the
+    // collector is recorded as a change to the minting account, i.e. fees
+    // are burned again.
+    let collector = fee_collector.unwrap_or(minting_account());
+    record_transaction(TokenTransaction {
+        id: next_id("tx"),
+        from: caller,
+        to: collector.owner,
+        amount: 0,
+        transaction_type: TransactionType::SetFeeCollector,
+        timestamp: time(),
+        memo: String::new(),
+        from_subaccount: None,
+        to_subaccount: collector.subaccount,
+        spender: None,
+        fee: None,
+        batch_id: None,
+        fee_collector: None,
+        metadata: None,
+        raw_memo: None,
+    });
+
+    Ok(get_fee_config())
+}
+
+#[query]
+pub fn get_token_metadata() -> TokenMetadata {
+    METADATA.with(|cell| cell.borrow().get().clone())
+}
+
+// Logged like fee collector changes. The block records the new name and
+// symbol and the hashes of a new logo or description; the memo lists the
+// fields that changed.
+#[update]
+pub fn update_token_metadata(update: TokenMetadataUpdate) -> Result<TokenMetadata, TokenError> {
+    let caller = ic_cdk::caller();
+    ensure_controller(caller)?;
+
+    let mut metadata = get_token_metadata();
+    let mut changed = Vec::new();
+    let change = MetadataChange {
+        name: update.name.clone(),
+        symbol: update.symbol.clone(),
+        logo_hash: update.logo.as_deref().map(|logo| Sha256::digest(logo).into()),
+        description_hash: update.description.as_deref().map(|description| Sha256::digest(description).into()),
+    };
+    if let Some(name) = update.name {
+        metadata.name = name;
+        changed.push("name");
+    }
+    if let Some(symbol) = update.symbol {
+        metadata.symbol = symbol;
+        changed.push("symbol");
+    }
+    if let Some(logo) = update.logo {
+        metadata.logo = (!logo.is_empty()).then_some(logo);
+        changed.push("logo");
+    }
+    if let Some(description) = update.description {
+        metadata.description = (!description.is_empty()).then_some(description);
+        changed.push("description");
+    }
+    if changed.is_empty() {
+        return Err(TokenError::GenericError {
+            message: "Nothing to update".to_string(),
+        });
+    }
+    validate_metadata(&metadata).map_err(|message| TokenError::GenericError { message })?;
+
+    METADATA.with(|cell| cell.borrow_mut().set(metadata.clone()).expect("failed to store token metadata"));
+    record_transaction(TokenTransaction {
+        id: next_id("tx"),
+        from: caller,
+        to: ic_cdk::id(),
+        amount: 0,
+        transaction_type: TransactionType::SetMetadata,
+        timestamp: time(),
+        memo: changed.join(","),
+        from_subaccount: None,
+        to_subaccount: None,
+        spender: None,
+        fee: None,
+        batch_id: None,
+        fee_collector: None,
+        metadata: Some(change),
+        raw_memo: None,
+    });
+
+    Ok(metadata)
+}
+
+#[update]
+pub fn set_dedup_window(window_nanos: u64) -> Result<u64, TokenError> {
+    ensure_controller(ic_cdk::caller())?;
+    mutate_state(|state| state.dedup_window_nanos = Some(window_nanos));
+    Ok(window_nanos)
+}
+
+#[query]
+pub fn get_dedup_window() -> u64 {
+    dedup_window()
+}
+
+#[query]
+pub fn get_fee_config() -> FeeConfig {
+    FeeConfig {
+        transfer_fee: transfer_fee(),
+        fee_collector: read_state(|state| state.fee_collector),
+    }
+}
+
+#[update]
+pub fn set_vesting_policy(policy: VestingPolicy) -> Result<VestingPolicy, TokenError> {
+    ensure_controller(ic_cdk::caller())?;
+    if policy.cliff_nanos > policy.duration_nanos {
+        return Err(TokenError::GenericError {
+            message: "Vesting cliff is after the end of vesting".to_string(),
+        });
+    }
+    mutate_state(|state| state.vesting_policy = Some(policy.clone()));
+    Ok(policy)
+}
+
+#[query]
+pub fn get_vesting_policy() -> VestingPolicy {
+    vesting_policy()
+}
+
+// Vesting grants of the principal, defaulting to the caller.
+#[query]
+pub fn get_vesting_grants(principal: Option<Principal>) -> Vec<VestingGrant> {
+    let beneficiary = Account::new(principal.unwrap_or(ic_cdk::caller()));
+
+    VESTING_GRANTS.with(|grants| {
+        grants
+            .borrow()
+            .iter()
+            .map(|(_, grant)| grant)
+            .filter(|grant| grant.beneficiary == beneficiary)
+            .collect()
+    })
+}
+
+// Spendable balance alongside reward tokens still vesting.
+#[query]
+pub fn get_balance_breakdown(principal: Option<Principal>) -> BalanceBreakdown {
+    let principal = principal.unwrap_or(ic_cdk::caller());
+
+    BalanceBreakdown {
+        spendable: get_balance(Some(principal)),
+        locked: get_vesting_grants(Some(principal)).iter().map(VestingGrant::locked).sum(),
+    }
+}
+
+// Account freezing
+
+#[update]
+pub fn freeze_account(principal: Principal, reason: FreezeReason, note: String) -> Result<FreezeRecord, TokenError> {
+    let caller = ic_cdk::caller();
+    ensure_controller(caller)?;
+    validate_freeze_note(&note)?;
+    if principal == ic_cdk::id() {
+        return Err(TokenError::GenericError {
+            message: "The ledger's own accounts cannot be frozen".to_string(),
+        });
+    }
+
+    let now = time();
+    let record = FreezeRecord {
+        principal,
+        reason,
+        note: note.clone(),
+        frozen_by: caller,
+        frozen_at: now,
+    };
+    FROZEN_ACCOUNTS.with(|frozen| frozen.borrow_mut().insert(principal, record.clone()));
+    log_freeze_event(principal, FreezeAction::Freeze, reason, note, caller, now);
+
+    Ok(record)
+}
+
+#[update]
+pub fn unfreeze_account(principal: Principal, note: String) -> Result<FreezeEvent, TokenError> {
+    let caller = ic_cdk::caller();
+    ensure_controller(caller)?;
+    validate_freeze_note(&note)?;
+
+    let record = FROZEN_ACCOUNTS
+        .with(|frozen| frozen.borrow_mut().remove(&principal))
+        .ok_or_else(|| TokenError::GenericError {
+            message: "Account is not frozen".to_string(),
+        })?;
+
+    let now = time();
+    release_withheld_rewards(principal, now);
+    // Holds that expired while the account was frozen are due now.
+    schedule_hold_expiry(now);
+
+    Ok(log_freeze_event(principal, FreezeAction::Unfreeze, record.reason, note, caller, now))
+}
+
+#[query]
+pub fn get_frozen_accounts() -> Vec<FreezeRecord> {
+    FROZEN_ACCOUNTS.with(|frozen| frozen.borrow().iter().map(|(_, record)| record).collect())
+}
+
+#[query]
+pub fn get_freeze_status(principal: Principal) -> Option<FreezeRecord> {
+    FROZEN_ACCOUNTS.with(|frozen| frozen.borrow().get(&principal))
+}
+
+// Freeze and unfreeze actions, oldest first, optionally for one principal.
+#[query]
+pub fn get_freeze_history(principal: Option<Principal>) -> Result<Vec<FreezeEvent>, TokenError> {
+    ensure_controller(ic_cdk::caller())?;
+
+    Ok(FREEZE_EVENTS.with(|events| {
+        events
+            .borrow()
+            .iter()
+            .map(|(_, event)| event)
+            .filter(|event| match principal {
+                Some(principal) => event.principal == principal,
+                None => true,
+            })
+            .collect()
+    }))
+}
+
+// Course staking
+
+#[update]
+pub fn stake(course_id: String, amount: u64) -> Result<Stake, TokenError> {
+    let caller = ic_cdk::caller();
+    let now = time();
+    let staker = Account::new(caller);
+    ensure_not_frozen(caller)?;
+
+    if course_id.is_empty() || course_id.len() > MAX_COURSE_ID_LENGTH {
+        return Err(TokenError::GenericError {
+            message: format!("Course id must be 1 to {} bytes", MAX_COURSE_ID_LENGTH),
+        });
+    }
+    if amount == 0 {
+        return Err(TokenError::GenericError {
+            message: "Stake amount must be positive".to_string(),
+        });
+    }
+
+    let key = StakeKey {
+        course_id: course_id.clone(),
+        staker,
+    };
+    let mut stake = STAKES.with(|stakes| stakes.borrow().get(&key)).unwrap_or(Stake {
+        course_id: course_id.clone(),
+        staker,
+        amount: 0,
+        rewards_earned: 0,
+        staked_at: now,
+        updated_at: now,
+        withheld_rewards: None,
+    });
+    stake.amount = stake.amount.checked_add(amount).ok_or(TokenError::Overflow)?;
+    stake.updated_at = now;
+
+    move_balance(staker, staking_account(), amount, 0, now)
+        .map_err(|balance| TokenError::InsufficientFunds { balance })?;
+    record_transaction(TokenTransaction {
+        id: next_id("tx"),
+        from: caller,
+        to: staking_account().owner,
+        amount,
+        transaction_type: TransactionType::Stake,
+        timestamp: now,
+        memo: format!("Stake on {}", course_id),
+        from_subaccount: None,
+        to_subaccount: staking_account().subaccount,
+        spender: None,
+        fee: None,
+        batch_id: None,
+        fee_collector: None,
+        metadata: None,
+        raw_memo: None,
+    });
+
+    STAKES.with(|stakes| stakes.borrow_mut().insert(key, stake.clone()));
+    add_course_stake(&course_id, amount as i128);
+
+    Ok(stake)
+}
+
+// Starts unbonding part of a stake. The tokens stop counting towards the
+// course right away and are paid back after the unbonding period.
+#[update]
+pub fn unstake(course_id: String, amount: u64) -> Result<Unbonding, TokenError> {
+    let caller = ic_cdk::caller();
+    let now = time();
+    let staker = Account::new(caller);
+    ensure_not_frozen(caller)?;
+
+    if amount == 0 {
+        return Err(TokenError::GenericError {
+            message: "Unstake amount must be positive".to_string(),
+        });
+    }
+
+    let key = StakeKey {
+        course_id: course_id.clone(),
+        staker,
+    };
+    let mut stake = STAKES.with(|stakes| stakes.borrow().get(&key)).ok_or_else(|| TokenError::GenericError {
+        message: format!("No stake on {}", course_id),
+    })?;
+    stake.amount = stake
+        .amount
+        .checked_sub(amount)
+        .ok_or(TokenError::InsufficientFunds { balance: stake.amount })?;
+    stake.updated_at = now;
+
+    if stake.amount == 0 {
+        STAKES.with(|stakes| stakes.borrow_mut().remove(&key));
+    } else {
+        STAKES.with(|stakes| stakes.borrow_mut().insert(key, stake));
+    }
+    add_course_stake(&course_id, -(amount as i128));
+
+    let unbonding = Unbonding {
+        id: next_sequence("unbonding"),
+        course_id,
+        staker,
+        amount,
+        started_at: now,
+        available_at: now.saturating_add(staking_config().unbonding_nanos),
+    };
+    UNBONDINGS.with(|unbondings| {
+        unbondings
+            .borrow_mut()
+            .insert((unbonding.available_at, unbonding.id), unbonding.clone())
+    });
+
+    Ok(unbonding)
+}
+
+// Stakes of the principal, defaulting to the caller.
+#[query]
+pub fn get_stakes(principal: Option<Principal>) -> Vec<Stake> {
+    let staker = Account::new(principal.unwrap_or(ic_cdk::caller()));
+
+    STAKES.with(|stakes| {
+        stakes
+            .borrow()
+            .iter()
+            .filter(|(key, _)| key.staker == staker)
+            .map(|(_, stake)| stake)
+            .collect()
+    })
+}
+
+#[query]
+pub fn get_unbondings(principal: Option<Principal>) -> Vec<Unbonding> {
+    let staker = Account::new(principal.unwrap_or(ic_cdk::caller()));
+
+    UNBONDINGS.with(|unbondings| {
+        unbondings
+            .borrow()
+            .iter()
+            .map(|(_, unbonding)| unbonding)
+            .filter(|unbonding| unbonding.staker == staker)
+            .collect()
+    })
+}
+
+// Stake totals for the given courses, for ranking them. Courses nobody has
+// staked on are reported with zero.
+#[query]
+pub fn get_course_stakes(course_ids: Vec<String>) -> Vec<CourseStake> {
+    course_ids
+        .into_iter()
+        .map(|course_id| CourseStake {
+            total_staked: COURSE_STAKE_TOTALS.with(|totals| totals.borrow().get(&course_id)).unwrap_or(0),
+            stakers: course_stakes(&course_id).len() as u64,
+            pending_rewards: CURATOR_POOLS.with(|pools| pools.borrow().get(&course_id)).unwrap_or(0),
+            course_id,
+        })
+        .collect()
+}
+
+#[update]
+pub fn set_staking_config(config: StakingConfig) -> Result<StakingConfig, TokenError> {
+    ensure_controller(ic_cdk::caller())?;
+    if config.curator_share_bps > 10_000 {
+        return Err(TokenError::GenericError {
+            message: "Curator share cannot exceed 10000 basis points".to_string(),
+        });
+    }
+    mutate_state(|state| state.staking_config = Some(config.clone()));
+    Ok(config)
+}
+
+#[query]
+pub fn get_staking_config() -> StakingConfig {
+    staking_config()
+}
+
+// Escrow holds
+
+#[update]
+pub fn create_hold(args: CreateHoldArgs) -> Result<Hold, TokenError> {
+    let caller = ic_cdk::caller();
+    let now = time();
+    let fee = transfer_fee();
+    let payer = args.from.unwrap_or(Account::new(caller));
+    ensure_not_frozen(payer.owner)?;
+
+    if args.refund_window_nanos > MAX_HOLD_WINDOW_NANOS {
+        return Err(TokenError::GenericError {
+            message: format!("Refund window is longer than {} nanoseconds", MAX_HOLD_WINDOW_NANOS),
+        });
+    }
+    if args.memo.len() > MAX_HOLD_MEMO_LENGTH {
+        return Err(TokenError::GenericError {
+            message: format!("Memo is longer than {} bytes", MAX_HOLD_MEMO_LENGTH),
+        });
+    }
+
+    let spender = Account::new(caller);
+    let debited = amount_with_fee(args.amount, fee)?;
+    let allowance = check_allowance(payer, spender, debited, now)
+        .map_err(|allowance| TokenError::InsufficientAllowance { allowance })?;
+
+    move_balance(payer, escrow_account(), args.amount, fee, now)
+        .map_err(|balance| TokenError::InsufficientFunds { balance })?;
+    use_allowance(payer, spender, allowance, debited);
+
+    let created_in_block = record_transaction(TokenTransaction {
+        id: next_id("tx"),
+        from: payer.owner,
+        to: escrow_account().owner,
+        amount: args.amount,
+        transaction_type: TransactionType::HoldCreate,
+        timestamp: now,
+        memo: args.memo.clone(),
+        from_subaccount: payer.subaccount,
+        to_subaccount: escrow_account().subaccount,
+        spender: (payer != spender).then_some(spender),
+        fee: Some(fee),
+        batch_id: None,
+        fee_collector: fee_collector_for(fee),
+        metadata: None,
+        raw_memo: None,
+    });
+
+    let hold = Hold {
+        id: next_sequence("hold"),
+        payer,
+        payee: args.payee,
+        amount: args.amount,
+        created_by: caller,
+        created_at: now,
+        expires_at: now.saturating_add(args.refund_window_nanos),
+        memo: args.memo,
+        status: HoldStatus::Active,
+        created_in_block,
+        resolved_at: None,
+        resolved_in_block: None,
+    };
+    HOLDS.with(|holds| holds.borrow_mut().insert(hold.id, hold.clone()));
+    HOLD_EXPIRIES.with(|expiries| expiries.borrow_mut().insert((hold.expires_at, hold.id), ()));
+    schedule_hold_expiry(now);
+
+    Ok(hold)
+}
+
+// Pays the payee early. Open to the payer and whoever created the hold, and
+// to anyone once the refund window has passed.
+#[update]
+pub fn release_hold(hold_id: u64) -> Result<Hold, TokenError> {
+    let caller = ic_cdk::caller();
+    let now = time();
+    let hold = active_hold(hold_id)?;
+
+    let is_payer = caller == hold.payer.owner || caller == hold.created_by;
+    if !is_payer && now < hold.expires_at {
+        ensure_controller(caller)?;
+    }
+
+    settle_hold(hold, HoldStatus::Released, now)
+}
+
+// Returns the tokens to the payer. The payee may refund at any time; the
+// payer and whoever created the hold only within the refund window.
+#[update]
+pub fn refund_hold(hold_id: u64) -> Result<Hold, TokenError> {
+    let caller = ic_cdk::caller();
+    let now = time();
+    let hold = active_hold(hold_id)?;
+
+    let is_payer = caller == hold.payer.owner || caller == hold.created_by;
+    let allowed = caller == hold.payee.owner || (is_payer && now < hold.expires_at);
+    if !allowed {
+        ensure_controller(caller)?;
+    }
+
+    settle_hold(hold, HoldStatus::Refunded, now)
+}
+
+#[query]
+pub fn get_hold(hold_id: u64) -> Option<Hold> {
+    HOLDS.with(|holds| holds.borrow().get(&hold_id))
+}
+
+// Holds the account pays or is paid by, defaulting to the caller's.
+#[query]
+pub fn get_holds(account: Option<Account>, status: Option<HoldStatus>) -> Vec<Hold> {
+    let account = account.unwrap_or(Account::new(ic_cdk::caller()));
+
+    HOLDS.with(|holds| {
+        holds
+            .borrow()
+            .iter()
+            .map(|(_, hold)| hold)
+            .filter(|hold| hold.payer == account || hold.payee == account)
+            .filter(|hold| match status {
+                Some(status) => hold.status == status,
+                None => true,
+            })
+            .collect()
+    })
+}
+
+// ICRC-1 interface
+
+#[query]
+pub fn icrc1_name() -> String {
+    get_token_metadata().name
+}
+
+#[query]
+pub fn icrc1_symbol() -> String {
+    get_token_metadata().symbol
+}
+
+#[query]
+pub fn icrc1_decimals() -> u8 {
+    get_token_metadata().decimals
+}
+
+#[query]
+pub fn icrc1_fee() -> Nat {
+    Nat::from(transfer_fee())
+}
+
+#[query]
+pub fn icrc1_metadata() -> Vec<(String, MetadataValue)> {
+    let metadata = get_token_metadata();
+    let mut entries = vec![
+        ("icrc1:name".to_string(), MetadataValue::Text(metadata.name)),
+        ("icrc1:symbol".to_string(), MetadataValue::Text(metadata.symbol)),
+        ("icrc1:decimals".to_string(), MetadataValue::Nat(Nat::from(metadata.decimals))),
+        ("icrc1:fee".to_string(), MetadataValue::Nat(Nat::from(transfer_fee()))),
+        ("icrc1:max_memo_length".to_string(), MetadataValue::Nat(Nat::from(MAX_MEMO_LENGTH))),
+    ];
+    if let Some(logo) = metadata.logo {
+        entries.push(("icrc1:logo".to_string(), MetadataValue::Text(logo)));
+    }
+    if let Some(description) = metadata.description {
+        entries.push(("scholar:description".to_string(), MetadataValue::Text(description)));
+    }
+    entries
+}
+
+#[query]
+pub fn icrc1_total_supply() -> Nat {
+    Nat::from(get_total_supply())
+}
+
+// Transfers to this account are burns.
+#[query]
+pub fn icrc1_minting_account() -> Option<Account> {
+    Some(minting_account())
+}
+
+#[query]
+pub fn icrc1_balance_of(account: Account) -> Nat {
+    BALANCES.with(|balances| {
+        Nat::from(balances.borrow().get(&account).map(|b| b.balance).unwrap_or(0))
+    })
+}
+
+#[query]
+pub fn icrc1_supported_standards() -> Vec<StandardRecord> {
+    vec![
+        StandardRecord {
+            name: "ICRC-1".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-1".to_string(),
+        },
+        StandardRecord {
+            name: "ICRC-2".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-2".to_string(),
+        },
+        StandardRecord {
+            name: "ICRC-3".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-3".to_string(),
+        },
+    ]
+}
+
+#[update]
+pub fn icrc1_transfer(arg: TransferArg) -> Result<Nat, TransferError> {
+    let caller = ic_cdk::caller();
+    let now = time();
+    let from = Account {
+        owner: caller,
+        subaccount: arg.from_subaccount,
+    };
+
+    ensure_not_frozen(caller).map_err(|err| TransferError::GenericError {
+        error_code: Nat::from(4u64),
+        message: format!("{:?}", err),
+    })?;
+
+    let amount = nat_to_u64(&arg.amount).ok_or_else(|| TransferError::GenericError {
+        error_code: Nat::from(0u64),
+        message: "Amount does not fit in 64 bits".to_string(),
+    })?;
+
+    let is_burn = arg.to == minting_account();
+    let fee = if is_burn { 0 } else { transfer_fee() };
+    if arg.fee.as_ref().is_some_and(|requested| nat_to_u64(requested) != Some(fee)) {
+        return Err(TransferError::BadFee {
+            expected_fee: Nat::from(fee),
+        });
+    }
+
+    let memo = decode_memo(arg.memo).map_err(|message| TransferError::GenericError {
+        error_code: Nat::from(1u64),
+        message,
+    })?;
+
+    let dedup = deduplicate(arg.created_at_time.map(|created_at_time| DedupKey {
+        caller,
+        transaction_type: if is_burn { &TransactionType::Burn } else { &TransactionType::Transfer },
+        from: Some(from),
+        to: Some(arg.to),
+        amount,
+        fee: arg.fee.as_ref().map(|_| fee),
+        memo: &memo,
+        created_at_time,
+    }), now)?;
+
+    if is_burn {
+        let (block_index, _) = burn(from, amount, memo, None, now)
+            .map_err(|err| match err {
+                TokenError::InsufficientFunds { balance } => TransferError::InsufficientFunds {
+                    balance: Nat::from(balance),
+                },
+                TokenError::BadBurn { min_burn_amount } => TransferError::BadBurn {
+                    min_burn_amount: Nat::from(min_burn_amount),
+                },
+                other => TransferError::GenericError {
+                    error_code: Nat::from(3u64),
+                    message: format!("{:?}", other),
+                },
+            })?;
+        remember_transaction(dedup, block_index, now);
+        return Ok(Nat::from(block_index));
+    }
+
+    move_balance(from, arg.to, amount, fee, now).map_err(|balance| TransferError::InsufficientFunds {
+        balance: Nat::from(balance),
+    })?;
+
+    let (memo, raw_memo) = split_memo(memo);
+    let block_index = record_transaction(TokenTransaction {
+        id: next_id("tx"),
+        from: caller,
+        to: arg.to.owner,
+        amount,
+        transaction_type: TransactionType::Transfer,
+        timestamp: now,
+        memo,
+        from_subaccount: arg.from_subaccount,
+        to_subaccount: arg.to.subaccount,
+        spender: None,
+        fee: Some(fee),
+        batch_id: None,
+        fee_collector: fee_collector_for(fee),
+        metadata: None,
+        raw_memo,
+    });
+    remember_transaction(dedup, block_index, now);
+
+    Ok(Nat::from(block_index))
+}
+
+// ICRC-2 interface
+
+#[update]
+pub fn icrc2_approve(args: ApproveArgs) -> Result<Nat, ApproveError> {
+    let caller = ic_cdk::caller();
+    let now = time();
+    let from = Account {
+        owner: caller,
+        subaccount: args.from_subaccount,
+    };
+
+    if from.owner == args.spender.owner {
+        return Err(ApproveError::GenericError {
+            error_code: Nat::from(2u64),
+            message: "Cannot approve an allowance for yourself".to_string(),
+        });
+    }
+
+    ensure_not_frozen(caller).map_err(|err| ApproveError::GenericError {
+        error_code: Nat::from(4u64),
+        message: format!("{:?}", err),
+    })?;
+
+    let amount = nat_to_u64(&args.amount).unwrap_or(u64::MAX);
+
+    let fee = transfer_fee();
+    if args.fee.as_ref().is_some_and(|requested| nat_to_u64(requested) != Some(fee)) {
+        return Err(ApproveError::BadFee {
+            expected_fee: Nat::from(fee),
+        });
+    }
+
+    if args.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(ApproveError::Expired { ledger_time: now });
+    }
+
+    let memo = decode_memo(args.memo).map_err(|message| ApproveError::GenericError {
+        error_code: Nat::from(1u64),
+        message,
+    })?;
+
+    let dedup = deduplicate(args.created_at_time.map(|created_at_time| DedupKey {
+        caller,
+        transaction_type: &TransactionType::Approve,
+        from: Some(from),
+        to: Some(args.spender),
+        amount,
+        fee: args.fee.as_ref().map(|_| fee),
+        memo: &memo,
+        created_at_time,
+    }), now)?;
+
+    ALLOWANCES.with(|allowances| {
+        let allowances_map = allowances.borrow();
+        let key = (from, args.spender);
+
+        if let Some(expected) = &args.expected_allowance {
+            let current = allowances_map
+                .get(&key)
+                .filter(|allowance| !allowance.is_expired(now))
+                .map(|allowance| allowance.allowance)
+                .unwrap_or(0);
+            if nat_to_u64(expected) != Some(current) {
+                return Err(ApproveError::AllowanceChanged {
+                    current_allowance: Nat::from(current),
+                });
+            }
+        }
+
+        Ok(())
+    })?;
+
+    // The approval fee is paid by the owner, like a transfer fee.
+    debit(from, fee, now).map_err(|balance| ApproveError::InsufficientFunds {
+        balance: Nat::from(balance),
+    })?;
+    collect_fee(fee, now);
+
+    ALLOWANCES.with(|allowances| {
+        let mut allowances_map = allowances.borrow_mut();
+        let key = (from, args.spender);
+        if amount == 0 {
+            allowances_map.remove(&key);
+        } else {
+            allowances_map.insert(key, Allowance {
+                allowance: amount,
+                expires_at: args.expires_at,
+            });
+        }
+    });
+
+    let (memo, raw_memo) = split_memo(memo);
+    let block_index = record_transaction(TokenTransaction {
+        id: next_id("tx"),
+        from: caller,
+        to: args.spender.owner,
+        amount,
+        transaction_type: TransactionType::Approve,
+        timestamp: now,
+        memo,
+        from_subaccount: args.from_subaccount,
+        to_subaccount: args.spender.subaccount,
+        spender: Some(args.spender),
+        fee: Some(fee),
+        batch_id: None,
+        fee_collector: fee_collector_for(fee),
+        metadata: None,
+        raw_memo,
+    });
+    remember_transaction(dedup, block_index, now);
+
+    Ok(Nat::from(block_index))
+}
+
+#[query]
+pub fn icrc2_allowance(args: AllowanceArgs) -> AllowanceResponse {
+    let now = time();
+
+    ALLOWANCES.with(|allowances| {
+        match allowances.borrow().get(&(args.account, args.spender)) {
+            Some(allowance) if !allowance.is_expired(now) => AllowanceResponse {
+                allowance: Nat::from(allowance.allowance),
+                expires_at: allowance.expires_at,
+            },
+            _ => AllowanceResponse {
+                allowance: Nat::from(0u64),
+                expires_at: None,
+            },
+        }
+    })
+}
+
+#[update]
+pub fn icrc2_transfer_from(args: TransferFromArgs) -> Result<Nat, TransferFromError> {
+    let caller = ic_cdk::caller();
+    let now = time();
+    let spender = Account {
+        owner: caller,
+        subaccount: args.spender_subaccount,
+    };
+
+    // A frozen owner's allowances cannot be drawn on either.
+    ensure_not_frozen(args.from.owner)
+        .and_then(|_| ensure_not_frozen(caller))
+        .map_err(|err| TransferFromError::GenericError {
+            error_code: Nat::from(4u64),
+            message: format!("{:?}", err),
+        })?;
+
+    let amount = nat_to_u64(&args.amount).ok_or_else(|| TransferFromError::GenericError {
+        error_code: Nat::from(0u64),
+        message: "Amount does not fit in 64 bits".to_string(),
+    })?;
+
+    let fee = transfer_fee();
+    if args.fee.as_ref().is_some_and(|requested| nat_to_u64(requested) != Some(fee)) {
+        return Err(TransferFromError::BadFee {
+            expected_fee: Nat::from(fee),
+        });
+    }
+
+    let memo = decode_memo(args.memo).map_err(|message| TransferFromError::GenericError {
+        error_code: Nat::from(1u64),
+        message,
+    })?;
+
+    let dedup = deduplicate(args.created_at_time.map(|created_at_time| DedupKey {
+        caller,
+        transaction_type: &TransactionType::TransferFrom,
+        from: Some(args.from),
+        to: Some(args.to),
+        amount,
+        fee: args.fee.as_ref().map(|_| fee),
+        memo: &memo,
+        created_at_time,
+    }), now)?;
+
+    // The allowance has to cover both the amount and the fee.
+    let debited = amount.saturating_add(fee);
+    let allowance = check_allowance(args.from, spender, debited, now).map_err(|allowance| {
+        TransferFromError::InsufficientAllowance {
+            allowance: Nat::from(allowance),
+        }
+    })?;
+
+    move_balance(args.from, args.to, amount, fee, now).map_err(|balance| {
+        TransferFromError::InsufficientFunds {
+            balance: Nat::from(balance),
+        }
+    })?;
+
+    use_allowance(args.from, spender, allowance, debited);
+
+    let (memo, raw_memo) = split_memo(memo);
+    let block_index = record_transaction(TokenTransaction {
+        id: next_id("tx"),
+        from: args.from.owner,
+        to: args.to.owner,
+        amount,
+        transaction_type: TransactionType::TransferFrom,
+        timestamp: now,
+        memo,
+        from_subaccount: args.from.subaccount,
+        to_subaccount: args.to.subaccount,
+        spender: Some(spender),
+        fee: Some(fee),
+        batch_id: None,
+        fee_collector: fee_collector_for(fee),
+        metadata: None,
+        raw_memo,
+    });
+    remember_transaction(dedup, block_index, now);
+
+    Ok(Nat::from(block_index))
+}
+
+// ICRC-3 interface
+
+#[query]
+pub fn icrc3_get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    let first_local = first_local_block();
+    BLOCKS.with(|blocks| {
+        let blocks_map = blocks.borrow();
+        let log_length = blocks_map.last_key_value().map(|(index, _)| index + 1).unwrap_or(0);
+
+        let mut result = Vec::new();
+        let mut archived: Vec<ArchivedBlocks> = Vec::new();
+        for arg in args {
+            let start = nat_to_u64(&arg.start).unwrap_or(u64::MAX);
+            let length = nat_to_u64(&arg.length).unwrap_or(u64::MAX).min(MAX_BLOCKS_PER_REQUEST);
+            let end = start.saturating_add(length);
+
+            // Point the caller at the archives holding the part of the
+            // range that is no longer local.
+            let mut archived_start = start;
+            while archived_start < end.min(first_local) {
+                let Some(range) = archive_for(archived_start) else {
+                    break;
+                };
+                let archived_end = end.min(range.end + 1);
+                let request = GetBlocksArgs {
+                    start: Nat::from(archived_start),
+                    length: Nat::from(archived_end - archived_start),
+                };
+                match archived.iter_mut().find(|a| a.callback.0.principal == range.canister_id) {
+                    Some(existing) => existing.args.push(request),
+                    None => archived.push(ArchivedBlocks {
+                        args: vec![request],
+                        callback: GetBlocksCallback::new(range.canister_id, "icrc3_get_blocks".to_string()),
+                    }),
+                }
+                archived_start = archived_end;
+            }
+
+            for (id, block) in blocks_map.range(start.max(first_local)..end) {
+                if result.len() as u64 >= MAX_BLOCKS_PER_REQUEST {
+                    break;
+                }
+                result.push(BlockWithId {
+                    id: Nat::from(id),
+                    block: block.to_value(),
+                });
+            }
+        }
+
+        GetBlocksResult {
+            log_length: Nat::from(log_length),
+            blocks: result,
+            archived_blocks: archived,
+        }
+    })
+}
+
+#[query]
+pub fn icrc3_get_archives(args: GetArchivesArgs) -> Vec<ICRC3ArchiveInfo> {
+    let ranges: Vec<ArchiveRange> = ARCHIVES.with(|archives| archives.borrow().iter().map(|(_, range)| range).collect());
+    // `from` skips archives up to and including the given one.
+    let skip = match args.from {
+        Some(from) => ranges.iter().position(|range| range.canister_id == from).map_or(0, |i| i + 1),
+        None => 0,
+    };
+    ranges
+        .into_iter()
+        .skip(skip)
+        .map(|range| ICRC3ArchiveInfo {
+            canister_id: range.canister_id,
+            start: Nat::from(range.start),
+            end: Nat::from(range.end),
+        })
+        .collect()
+}
+
+#[query]
+pub fn icrc3_get_tip_certificate() -> Option<ICRC3DataCertificate> {
+    let certificate = data_certificate()?;
+    let (index, hash) = last_block()?;
+
+    let mut hash_tree = vec![];
+    let mut serializer = serde_cbor::Serializer::new(&mut hash_tree);
+    serializer.self_describe().ok()?;
+    tip_tree(index, hash).serialize(&mut serializer).ok()?;
+
+    Some(ICRC3DataCertificate {
+        certificate,
+        hash_tree,
+    })
+}
+
+#[query]
+pub fn icrc3_supported_block_types() -> Vec<SupportedBlockType> {
+    ["1burn", "1mint", "1xfer", "2approve", "2xfer"]
+        .iter()
+        .map(|block_type| SupportedBlockType {
+            block_type: block_type.to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-3".to_string(),
+        })
+        .collect()
+}
+
+fn next_id(kind: &str) -> String {
+    ID_COUNTERS.with(|counters| counters.borrow_mut().next_id(kind))
+}
+
+fn next_sequence(kind: &str) -> u64 {
+    ID_COUNTERS.with(|counters| counters.borrow_mut().next_sequence(kind))
+}
+
+// Transaction and hold IDs are salted so they can't be predicted. The
+// salt comes from raw_rand, which init and post_upgrade can't await.
+fn schedule_id_salt() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        ic_cdk::spawn(async {
+            match raw_rand_salt().await {
+                Ok(salt) => ID_COUNTERS.with(|counters| counters.borrow_mut().set_salt(Some(salt))),
+                Err(message) => ic_cdk::print(format!("ID salt not set: {}", message)),
+            }
+        })
+    });
+}
+
+fn read_state<R>(f: impl FnOnce(&LedgerState) -> R) -> R {
+    STATE.with(|state| f(state.borrow().get()))
+}
+
+fn mutate_state<R>(f: impl FnOnce(&mut LedgerState) -> R) -> R {
+    STATE.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let mut state = cell.get().clone();
+        let result = f(&mut state);
+        cell.set(state).expect("failed to write ledger state");
+        result
+    })
+}
+
+fn sum_of_balances() -> u64 {
+    BALANCES.with(|balances| balances.borrow().iter().map(|(_, b)| b.balance).sum())
+}
+
+fn ensure_controller(caller: Principal) -> Result<(), TokenError> {
+    if ic_cdk::api::is_controller(&caller) {
+        Ok(())
+    } else {
+        Err(TokenError::Unauthorized { caller })
+    }
+}
+
+fn validate_metadata(metadata: &TokenMetadata) -> Result<(), String> {
+    if metadata.name.is_empty() || metadata.name.len() > MAX_TOKEN_NAME_LENGTH {
+        return Err(format!("Name must be 1 to {} bytes", MAX_TOKEN_NAME_LENGTH));
+    }
+    if metadata.symbol.is_empty() || metadata.symbol.len() > MAX_TOKEN_SYMBOL_LENGTH {
+        return Err(format!("Symbol must be 1 to {} bytes", MAX_TOKEN_SYMBOL_LENGTH));
+    }
+    if let Some(logo) = &metadata.logo {
+        if !logo.starts_with("data:") {
+            return Err("Logo must be a data URL".to_string());
+        }
+        if logo.len() > MAX_TOKEN_LOGO_LENGTH {
+            return Err(format!("Logo is longer than {} bytes", MAX_TOKEN_LOGO_LENGTH));
+        }
+    }
+    if metadata.description.as_ref().is_some_and(|description| description.len() > MAX_TOKEN_DESCRIPTION_LENGTH) {
+        return Err(format!("Description is longer than {} bytes", MAX_TOKEN_DESCRIPTION_LENGTH));
+    }
+    Ok(())
+}
+
+fn reward_claim_key(student: Principal, course_id: &str) -> String {
+    format!("{}_{}", student.to_text(), course_id)
+}
+
+fn ensure_reward_unclaimed(student: Principal, course_id: &str) -> Result<(), TokenError> {
+    match REWARD_CLAIMS.with(|claims| claims.borrow().get(&reward_claim_key(student, course_id))) {
+        Some(claim) => Err(TokenError::Duplicate {
+            duplicate_of: claim.block_index,
+        }),
+        None => Ok(()),
+    }
+}
+
+fn ensure_minter(caller: Principal) -> Result<(), TokenError> {
+    if MINTERS.with(|minters| minters.borrow().contains_key(&caller)) {
+        Ok(())
+    } else {
+        Err(TokenError::Unauthorized { caller })
+    }
+}
+
+fn mint_caps() -> MintCaps {
+    read_state(|state| state.mint_caps.clone()).unwrap_or(MintCaps {
+        minter_daily_cap: DEFAULT_MINTER_DAILY_CAP,
+        global_daily_cap: DEFAULT_GLOBAL_DAILY_CAP,
+    })
+}
+
+// First hour bucket still inside the window ending at `now`.
+fn mint_window_start(now: u64) -> u64 {
+    (now / MINT_BUCKET_NANOS).saturating_sub(MINT_WINDOW_BUCKETS - 1)
+}
+
+fn mint_quota(minter: Principal, now: u64) -> MintQuota {
+    let caps = mint_caps();
+    let window_start = mint_window_start(now);
+    let minter_daily_cap = MINTER_DAILY_CAPS
+        .with(|caps| caps.borrow().get(&minter))
+        .unwrap_or(caps.minter_daily_cap);
+
+    let minter_minted = MINTER_EMISSIONS.with(|emissions| {
+        emissions
+            .borrow()
+            .range((minter, window_start)..=(minter, u64::MAX))
+            .map(|(_, amount)| amount)
+            .fold(0u64, u64::saturating_add)
+    });
+    let global_minted = GLOBAL_EMISSIONS.with(|emissions| {
+        emissions
+            .borrow()
+            .range(window_start..)
+            .map(|(_, amount)| amount)
+            .fold(0u64, u64::saturating_add)
+    });
+
+    MintQuota {
+        minter,
+        minter_daily_cap,
+        minter_minted,
+        minter_remaining: minter_daily_cap.saturating_sub(minter_minted),
+        global_daily_cap: caps.global_daily_cap,
+        global_minted,
+        global_remaining: caps.global_daily_cap.saturating_sub(global_minted),
+    }
+}
+
+fn check_mint_quota(minter: Principal, amount: u64, now: u64) -> Result<(), TokenError> {
+    let quota = mint_quota(minter, now);
+    let remaining = quota.minter_remaining.min(quota.global_remaining);
+    if amount > remaining {
+        return Err(TokenError::MintCapExceeded { remaining });
+    }
+    Ok(())
+}
+
+// Adds a completed mint to the current hour's buckets and drops buckets
+// that have left the window.
+fn record_emission(minter: Principal, amount: u64, now: u64) {
+    let bucket = now / MINT_BUCKET_NANOS;
+    let window_start = mint_window_start(now);
+
+    MINTER_EMISSIONS.with(|emissions| {
+        let mut emissions_map = emissions.borrow_mut();
+        let expired: Vec<(Principal, u64)> = emissions_map
+            .range((minter, 0)..(minter, window_start))
+            .map(|(key, _)| key)
+            .collect();
+        for key in expired {
+            emissions_map.remove(&key);
+        }
+        let minted = emissions_map.get(&(minter, bucket)).unwrap_or(0);
+        emissions_map.insert((minter, bucket), minted.saturating_add(amount));
+    });
+
+    GLOBAL_EMISSIONS.with(|emissions| {
+        let mut emissions_map = emissions.borrow_mut();
+        let expired: Vec<u64> = emissions_map.range(..window_start).map(|(key, _)| key).collect();
+        for key in expired {
+            emissions_map.remove(&key);
+        }
+        let minted = emissions_map.get(&bucket).unwrap_or(0);
+        emissions_map.insert(bucket, minted.saturating_add(amount));
+    });
+}
+
+fn ensure_not_frozen(principal: Principal) -> Result<(), TokenError> {
+    match FROZEN_ACCOUNTS.with(|frozen| frozen.borrow().get(&principal)) {
+        Some(record) => Err(TokenError::AccountFrozen {
+            principal,
+            reason: record.reason,
+        }),
+        None => Ok(()),
+    }
+}
+
+fn validate_freeze_note(note: &str) -> Result<(), TokenError> {
+    if note.len() > MAX_FREEZE_NOTE_LENGTH {
+        return Err(TokenError::GenericError {
+            message: format!("Note is longer than {} bytes", MAX_FREEZE_NOTE_LENGTH),
+        });
+    }
+    Ok(())
+}
+
+fn log_freeze_event(
+    principal: Principal,
+    action: FreezeAction,
+    reason: FreezeReason,
+    note: String,
+    admin: Principal,
+    now: u64,
+) -> FreezeEvent {
+    let event = FreezeEvent {
+        id: next_sequence("freeze_event"),
+        principal,
+        action,
+        reason,
+        note,
+        admin,
+        timestamp: now,
+    };
+    FREEZE_EVENTS.with(|events| events.borrow_mut().insert(event.id, event.clone()));
+    event
+}
+
+fn record_minter_change(
+    admin: Principal,
+    minter: Principal,
+    transaction_type: TransactionType,
+    now: u64,
+) -> TokenTransaction {
+    let transaction = TokenTransaction {
+        id: next_id("tx"),
+        from: admin,
+        to: minter,
+        amount: 0,
+        transaction_type,
+        timestamp: now,
+        memo: String::new(),
+        from_subaccount: None,
+        to_subaccount: None,
+        spender: None,
+        fee: None,
+        batch_id: None,
+        fee_collector: None,
+        metadata: None,
+        raw_memo: None,
+    };
+    record_transaction(transaction.clone());
+    transaction
+}
+
+fn nat_to_u64(amount: &Nat) -> Option<u64> {
+    u64::try_from(&amount.0).ok()
+}
+
+// Memos on the legacy endpoints are text but share the ICRC-1 limit, so a
+// block always fits its storage bound.
+fn check_memo(memo: &str) -> Result<(), TokenError> {
+    if memo.len() > MAX_MEMO_LENGTH {
+        return Err(TokenError::MemoTooLong {
+            max_length: MAX_MEMO_LENGTH as u64,
+        });
+    }
+    Ok(())
+}
+
+fn decode_memo(memo: Option<Vec<u8>>) -> Result<Vec<u8>, String> {
+    let memo = memo.unwrap_or_default();
+    if memo.len() > MAX_MEMO_LENGTH {
+        return Err(format!("Memo is longer than {} bytes", MAX_MEMO_LENGTH));
+    }
+    Ok(memo)
+}
+
+// Splits ICRC memo bytes into the transaction's `memo` and `raw_memo`. The
+// raw bytes are only kept when they are not valid UTF-8.
+fn split_memo(memo: Vec<u8>) -> (String, Option<Vec<u8>>) {
+    match String::from_utf8(memo) {
+        Ok(text) => (text, None),
+        Err(err) => (String::from_utf8_lossy(err.as_bytes()).into_owned(), Some(err.into_bytes())),
+    }
+}
+
+// Moves `amount` from one account to another and charges `fee` to the
+// sender. On insufficient funds the sender's current balance is returned and
+// nothing is changed.
+fn move_balance(from: Account, to: Account, amount: u64, fee: u64, now: u64) -> Result<(), u64> {
+    debit(from, amount.saturating_add(fee), now)?;
+    // Balances never sum to more than the total supply, so crediting tokens
+    // that were just debited cannot overflow.
+    credit(to, amount, now).expect("balance exceeds total supply");
+    collect_fee(fee, now);
+    Ok(())
+}
+
+fn debit(account: Account, amount: u64, now: u64) -> Result<(), u64> {
+    BALANCES.with(|balances| {
+        let mut balances_map = balances.borrow_mut();
+        let mut balance = balances_map.get(&account).unwrap_or(TokenBalance {
+            owner: account.owner,
+            balance: 0,
+            updated_at: now,
+        });
+        balance.balance = balance.balance.checked_sub(amount).ok_or(balance.balance)?;
+        balance.updated_at = now;
+        checkpoint_balance(account, balance.balance, now);
+        balances_map.insert(account, balance);
+        Ok(())
+    })
+}
+
+fn credit(account: Account, amount: u64, now: u64) -> Result<(), TokenError> {
+    BALANCES.with(|balances| {
+        let mut balances_map = balances.borrow_mut();
+        let mut balance = balances_map.get(&account).unwrap_or(TokenBalance {
+            owner: account.owner,
+            balance: 0,
+            updated_at: now,
+        });
+        balance.balance = balance.balance.checked_add(amount).ok_or(TokenError::Overflow)?;
+        balance.updated_at = now;
+        checkpoint_balance(account, balance.balance, now);
+        balances_map.insert(account, balance);
+        Ok(())
+    })
+}
+
+// Balance changes are applied before the block recording them is appended,
+// so they belong to the next block in the log.
+fn checkpoint_balance(account: Account, balance: u64, now: u64) {
+    let block_index = last_block_index().map(|index| index + 1).unwrap_or(0);
+    BALANCE_CHECKPOINTS.with(|checkpoints| {
+        checkpoints.borrow_mut().insert((account, block_index), BalanceCheckpoint { balance, changed_at: now })
+    });
+}
+
+// Balance once block `block_index` had been applied.
+fn balance_at_block(account: Account, block_index: u64) -> u64 {
+    BALANCE_CHECKPOINTS.with(|checkpoints| {
+        checkpoints
+            .borrow()
+            .range((account, 0)..=(account, block_index))
+            .next_back()
+            .map(|(_, checkpoint)| checkpoint.balance)
+            .unwrap_or(0)
+    })
+}
+
+// Balance at the end of `timestamp`. Walks back from the latest checkpoint,
+// so recent times are cheapest.
+fn balance_at(account: Account, timestamp: u64) -> u64 {
+    BALANCE_CHECKPOINTS.with(|checkpoints| {
+        checkpoints
+            .borrow()
+            .range((account, 0)..=(account, u64::MAX))
+            .rev()
+            .find(|(_, checkpoint)| checkpoint.changed_at <= timestamp)
+            .map(|(_, checkpoint)| checkpoint.balance)
+            .unwrap_or(0)
+    })
+}
+
+// Creates `amount` new tokens in `to`. Nothing changes if either the balance
+// or the total supply would overflow.
+fn mint(to: Account, amount: u64, now: u64) -> Result<(), TokenError> {
+    let total_supply = read_state(|state| state.total_supply)
+        .checked_add(amount)
+        .ok_or(TokenError::Overflow)?;
+    credit(to, amount, now)?;
+    mutate_state(|state| state.total_supply = total_supply);
+    Ok(())
+}
+
+fn reduce_supply(amount: u64) {
+    mutate_state(|state| {
+        state.total_supply = state
+            .total_supply
+            .checked_sub(amount)
+            .expect("burned more than the total supply");
+    });
+}
+
+// Pays an already-debited fee to the fee collector, or burns it.
+fn collect_fee(fee: u64, now: u64) {
+    if fee == 0 {
+        return;
+    }
+    match read_state(|state| state.fee_collector) {
+        Some(collector) => credit(collector, fee, now).expect("balance exceeds total supply"),
+        None => reduce_supply(fee),
+    }
+}
+
+// The account a fee charged now is credited to, for the block's `fee_col`.
+fn fee_collector_for(fee: u64) -> Option<Account> {
+    if fee == 0 {
+        return None;
+    }
+    read_state(|state| state.fee_collector)
+}
+
+fn amount_with_fee(amount: u64, fee: u64) -> Result<u64, TokenError> {
+    amount.checked_add(fee).ok_or(TokenError::Overflow)
+}
+
+// The allowance `spender` draws on when spending `amount` from `owner`, or
+// `None` when owners spend their own funds. Fails with the current allowance
+// if it does not cover `amount`.
+fn check_allowance(owner: Account, spender: Account, amount: u64, now: u64) -> Result<Option<Allowance>, u64> {
+    if owner == spender {
+        return Ok(None);
+    }
+    let allowance = ALLOWANCES.with(|allowances| {
+        allowances
+            .borrow()
+            .get(&(owner, spender))
+            .filter(|allowance| !allowance.is_expired(now))
+    });
+    match allowance {
+        Some(allowance) if allowance.allowance >= amount => Ok(Some(allowance)),
+        other => Err(other.map(|a| a.allowance).unwrap_or(0)),
+    }
+}
+
+// Deducts `amount` from an allowance returned by `check_allowance`.
+fn use_allowance(owner: Account, spender: Account, allowance: Option<Allowance>, amount: u64) {
+    let Some(mut allowance) = allowance else {
+        return;
+    };
+    ALLOWANCES.with(|allowances| {
+        let mut allowances_map = allowances.borrow_mut();
+        allowance.allowance -= amount;
+        if allowance.allowance == 0 {
+            allowances_map.remove(&(owner, spender));
+        } else {
+            allowances_map.insert((owner, spender), allowance);
+        }
+    });
+}
+
+fn dedup_window() -> u64 {
+    read_state(|state| state.dedup_window_nanos.unwrap_or(DEFAULT_DEDUP_WINDOW_NANOS))
+}
+
+// Validates `created_at_time` and rejects requests already seen inside the
+// window. Returns the key to pass to `remember_transaction` once the request
+// has been applied; requests without `created_at_time` are not deduplicated.
+fn deduplicate(key: Option<DedupKey>, now: u64) -> Result<Option<(u64, Hash)>, DedupError> {
+    let Some(key) = key else {
+        return Ok(None);
+    };
+    let created_at_time = key.created_at_time;
+
+    if created_at_time.saturating_add(dedup_window()) < now {
+        return Err(DedupError::TooOld);
+    }
+    if created_at_time > now.saturating_add(PERMITTED_DRIFT_NANOS) {
+        return Err(DedupError::CreatedInFuture { ledger_time: now });
+    }
+
+    let hash: Hash = Sha256::digest(candid::encode_one(&key).expect("failed to encode dedup key")).into();
+    if let Some(duplicate_of) = RECENT_TRANSACTIONS.with(|recent| recent.borrow().get(&hash)) {
+        return Err(DedupError::Duplicate { duplicate_of });
+    }
+    Ok(Some((created_at_time, hash)))
+}
+
+fn remember_transaction(dedup: Option<(u64, Hash)>, block_index: u64, now: u64) {
+    prune_recent_transactions(now);
+
+    if let Some((created_at_time, hash)) = dedup {
+        RECENT_TRANSACTIONS.with(|recent| recent.borrow_mut().insert(hash, block_index));
+        RECENT_TRANSACTIONS_BY_TIME.with(|by_time| by_time.borrow_mut().insert((created_at_time, hash), ()));
+    }
+}
+
+// Forgets requests that can no longer be replayed, a bounded number per call.
+fn prune_recent_transactions(now: u64) {
+    let cutoff = now.saturating_sub(dedup_window().saturating_add(PERMITTED_DRIFT_NANOS));
+    let expired: Vec<(u64, Hash)> = RECENT_TRANSACTIONS_BY_TIME.with(|by_time| {
+        by_time
+            .borrow()
+            .range(..(cutoff, [0; 32]))
+            .take(MAX_DEDUP_PRUNE)
+            .map(|(key, _)| key)
+            .collect()
+    });
+
+    for key in expired {
+        RECENT_TRANSACTIONS_BY_TIME.with(|by_time| by_time.borrow_mut().remove(&key));
+        RECENT_TRANSACTIONS.with(|recent| recent.borrow_mut().remove(&key.1));
+    }
+}
+
+fn minting_account() -> Account {
+    Account::new(ic_cdk::id())
+}
+
+fn escrow_account() -> Account {
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(ESCROW_SUBACCOUNT),
+    }
+}
+
+fn vesting_account() -> Account {
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(VESTING_SUBACCOUNT),
+    }
+}
+
+fn vesting_policy() -> VestingPolicy {
+    read_state(|state| state.vesting_policy.clone()).unwrap_or(VestingPolicy {
+        threshold: DEFAULT_VESTING_THRESHOLD,
+        cliff_nanos: DEFAULT_VESTING_CLIFF_NANOS,
+        duration_nanos: DEFAULT_VESTING_DURATION_NANOS,
+    })
+}
+
+fn start_vesting_timer() {
+    ic_cdk_timers::set_timer_interval(VESTING_RELEASE_INTERVAL, release_vested_tokens);
+}
+
+// Pays out whatever has vested since the last pass, a bounded number of
+// grants per call.
+fn release_vested_tokens() {
+    let now = time();
+    let cursor = VESTING_CURSOR.get();
+    let mut ids: Vec<u64> = ACTIVE_VESTING_GRANTS.with(|active| {
+        active.borrow().range(cursor..).take(MAX_VESTING_RELEASES).map(|(id, _)| id).collect()
+    });
+    if ids.len() < MAX_VESTING_RELEASES {
+        // Wrap around to the grants before the cursor.
+        let remaining = MAX_VESTING_RELEASES - ids.len();
+        ids.extend(ACTIVE_VESTING_GRANTS.with(|active| {
+            active.borrow().range(..cursor).take(remaining).map(|(id, _)| id).collect::<Vec<_>>()
+        }));
+    }
+    VESTING_CURSOR.set(ids.last().map(|id| id + 1).unwrap_or(0));
+
+    for id in ids {
+        if let Some(grant) = VESTING_GRANTS.with(|grants| grants.borrow().get(&id)) {
+            release_grant(grant, now);
+        }
+    }
+}
+
+// Grants of frozen beneficiaries keep vesting and are paid out on the
+// first pass after they are unfrozen.
+fn release_grant(mut grant: VestingGrant, now: u64) {
+    let amount = grant.vested(now) - grant.released;
+    if amount == 0 || ensure_not_frozen(grant.beneficiary.owner).is_err() {
+        return;
+    }
+
+    move_balance(vesting_account(), grant.beneficiary, amount, 0, now)
+        .expect("vesting account does not cover an active grant");
+    record_transaction(TokenTransaction {
+        id: next_id("tx"),
+        from: vesting_account().owner,
+        to: grant.beneficiary.owner,
+        amount,
+        transaction_type: TransactionType::VestingRelease,
+        timestamp: now,
+        memo: format!("Vesting grant {}", grant.id),
+        from_subaccount: vesting_account().subaccount,
+        to_subaccount: grant.beneficiary.subaccount,
+        spender: None,
+        fee: None,
+        batch_id: None,
+        fee_collector: None,
+        metadata: None,
+        raw_memo: None,
+    });
+
+    grant.released += amount;
+    if grant.locked() == 0 {
+        ACTIVE_VESTING_GRANTS.with(|active| active.borrow_mut().remove(&grant.id));
+    }
+    VESTING_GRANTS.with(|grants| grants.borrow_mut().insert(grant.id, grant));
+}
+
+fn staking_account() -> Account {
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(STAKING_SUBACCOUNT),
+    }
+}
+
+fn curator_pool_account() -> Account {
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(CURATOR_POOL_SUBACCOUNT),
+    }
+}
+
+fn staking_config() -> StakingConfig {
+    read_state(|state| state.staking_config.clone()).unwrap_or(StakingConfig {
+        unbonding_nanos: DEFAULT_UNBONDING_NANOS,
+        curator_share_bps: DEFAULT_CURATOR_SHARE_BPS,
+    })
+}
+
+fn add_course_stake(course_id: &str, delta: i128) {
+    COURSE_STAKE_TOTALS.with(|totals| {
+        let mut totals_map = totals.borrow_mut();
+        let total = totals_map.get(&course_id.to_string()).unwrap_or(0) as i128 + delta;
+        if total > 0 {
+            totals_map.insert(course_id.to_string(), total as u64);
+        } else {
+            totals_map.remove(&course_id.to_string());
+        }
+    });
+}
+
+fn course_stakes(course_id: &str) -> Vec<(StakeKey, Stake)> {
+    // The management canister's principal is empty, so it sorts first.
+    let start = StakeKey {
+        course_id: course_id.to_string(),
+        staker: Account::new(Principal::management_canister()),
+    };
+    STAKES.with(|stakes| {
+        stakes
+            .borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.course_id == course_id)
+            .collect()
+    })
+}
+
+// The curators' share of a course reward: nothing while the emission is
+// off or nobody has staked on the course.
+fn curator_share(course_id: &str, reward: u64) -> u64 {
+    let staked = COURSE_STAKE_TOTALS.with(|totals| totals.borrow().contains_key(&course_id.to_string()));
+    if !staked {
+        return 0;
+    }
+    basis_points(reward, staking_config().curator_share_bps)
+}
+
+fn basis_points(amount: u64, bps: u64) -> u64 {
+    (amount as u128 * bps as u128 / 10_000) as u64
+}
+
+// Mints the curators' share of a course reward into the course's pool. The
+// share counts against the minter's caps like any other mint.
+fn fund_curator_pool(course_id: &str, share: u64, minter: Principal, now: u64) -> Result<(), TokenError> {
+    if share == 0 {
+        return Ok(());
+    }
+    mint(curator_pool_account(), share, now)?;
+    record_emission(minter, share, now);
+
+    record_transaction(TokenTransaction {
+        id: next_id("tx"),
+        from: minter,
+        to: curator_pool_account().owner,
+        amount: share,
+        transaction_type: TransactionType::Mint,
+        timestamp: now,
+        memo: format!("Curator share for {}", course_id),
+        from_subaccount: None,
+        to_subaccount: curator_pool_account().subaccount,
+        spender: None,
+        fee: None,
+        batch_id: None,
+        fee_collector: None,
+        metadata: None,
+        raw_memo: None,
+    });
+    CURATOR_POOLS.with(|pools| {
+        let mut pools_map = pools.borrow_mut();
+        let pending = pools_map.get(&course_id.to_string()).unwrap_or(0);
+        pools_map.insert(course_id.to_string(), pending + share);
+    });
+    Ok(())
+}
+
+fn pay_curator_reward(stake: &mut Stake, amount: u64, now: u64) {
+    move_balance(curator_pool_account(), stake.staker, amount, 0, now)
+        .expect("curator pool does not cover pending rewards");
+    record_transaction(TokenTransaction {
+        id: next_id("tx"),
+        from: curator_pool_account().owner,
+        to: stake.staker.owner,
+        amount,
+        transaction_type: TransactionType::CuratorReward,
+        timestamp: now,
+        memo: format!("Curator reward for {}", stake.course_id),
+        from_subaccount: curator_pool_account().subaccount,
+        to_subaccount: stake.staker.subaccount,
+        spender: None,
+        fee: None,
+        batch_id: None,
+        fee_collector: None,
+        metadata: None,
+        raw_memo: None,
+    });
+    stake.rewards_earned = stake.rewards_earned.saturating_add(amount);
+}
+
+// Pays out curator rewards withheld while `principal` was frozen.
+fn release_withheld_rewards(principal: Principal, now: u64) {
+    let withheld: Vec<(StakeKey, Stake)> = STAKES.with(|stakes| {
+        stakes
+            .borrow()
+            .iter()
+            .filter(|(key, stake)| key.staker.owner == principal && stake.withheld_rewards.is_some())
+            .collect()
+    });
+    for (key, mut stake) in withheld {
+        let amount = stake.withheld_rewards.take().unwrap_or(0);
+        if amount > 0 {
+            pay_curator_reward(&mut stake, amount, now);
+        }
+        STAKES.with(|stakes| stakes.borrow_mut().insert(key, stake));
+    }
+}
+
+fn start_staking_timer() {
+    ic_cdk_timers::set_timer_interval(STAKING_INTERVAL, || {
+        let now = time();
+        release_unbonded_stakes(now);
+        distribute_curator_rewards(now);
+    });
+}
+
+// Unbondings of frozen stakers are left for a later pass.
+fn release_unbonded_stakes(now: u64) {
+    let matured: Vec<((u64, u64), Unbonding)> = UNBONDINGS.with(|unbondings| {
+        unbondings
+            .borrow()
+            .range(..(now, u64::MAX))
+            .filter(|(_, unbonding)| ensure_not_frozen(unbonding.staker.owner).is_ok())
+            .take(MAX_UNBONDING_RELEASES)
+            .collect()
+    });
+
+    for (key, unbonding) in matured {
+        move_balance(staking_account(), unbonding.staker, unbonding.amount, 0, now)
+            .expect("staking account does not cover an unbonding");
+        record_transaction(TokenTransaction {
+            id: next_id("tx"),
+            from: staking_account().owner,
+            to: unbonding.staker.owner,
+            amount: unbonding.amount,
+            transaction_type: TransactionType::Unstake,
+            timestamp: now,
+            memo: format!("Unstake from {}", unbonding.course_id),
+            from_subaccount: staking_account().subaccount,
+            to_subaccount: unbonding.staker.subaccount,
+            spender: None,
+            fee: None,
+            batch_id: None,
+            fee_collector: None,
+            metadata: None,
+            raw_memo: None,
+        });
+        UNBONDINGS.with(|unbondings| unbondings.borrow_mut().remove(&key));
+    }
+}
+
+// Splits `pool` in proportion to `stakes`. Rounding leftovers go to the
+// largest stake so the shares always add up to the pool.
+fn pro_rata_shares(pool: u64, stakes: &[u64]) -> Vec<u64> {
+    let total: u128 = stakes.iter().map(|&stake| stake as u128).sum();
+    if total == 0 {
+        return vec![0; stakes.len()];
+    }
+    let mut shares: Vec<u64> = stakes
+        .iter()
+        .map(|&stake| (pool as u128 * stake as u128 / total) as u64)
+        .collect();
+    if let Some(largest) = (0..stakes.len()).max_by_key(|&i| stakes[i]) {
+        shares[largest] += pool - shares.iter().sum::<u64>();
+    }
+    shares
+}
+
+// Empties each course's pool into its stakers' accounts in proportion to
+// their stake; the rounding remainder goes to the largest stake. Pools of
+// courses nobody stakes on any more wait for new stakers. Shares are queued
+// when a pool is split and at most MAX_CURATOR_PAYOUTS are paid per tick.
+fn distribute_curator_rewards(now: u64) {
+    if CURATOR_PAYOUTS.with(|payouts| payouts.borrow().is_empty()) {
+        queue_curator_payouts();
+    }
+
+    let due: Vec<(StakeKey, u64)> =
+        CURATOR_PAYOUTS.with(|payouts| payouts.borrow().iter().take(MAX_CURATOR_PAYOUTS).collect());
+    for (key, share) in due {
+        CURATOR_PAYOUTS.with(|payouts| payouts.borrow_mut().remove(&key));
+        // A staker who left before being paid forfeits the share to the
+        // course's next distribution.
+        let Some(mut stake) = STAKES.with(|stakes| stakes.borrow().get(&key)) else {
+            CURATOR_POOLS.with(|pools| {
+                let mut pools_map = pools.borrow_mut();
+                let pending = pools_map.get(&key.course_id).unwrap_or(0);
+                pools_map.insert(key.course_id.clone(), pending + share);
+            });
+            continue;
+        };
+        if ensure_not_frozen(stake.staker.owner).is_err() {
+            stake.withheld_rewards = Some(stake.withheld_rewards.unwrap_or(0).saturating_add(share));
+        } else {
+            pay_curator_reward(&mut stake, share, now);
+        }
+        STAKES.with(|stakes| stakes.borrow_mut().insert(key, stake));
+    }
+}
+
+// Splits the next pools among their current stakers and queues the shares.
+fn queue_curator_payouts() {
+    let pools: Vec<(String, u64)> = CURATOR_POOLS.with(|pools| {
+        pools
+            .borrow()
+            .iter()
+            .filter(|(course_id, pending)| {
+                *pending > 0 && COURSE_STAKE_TOTALS.with(|totals| totals.borrow().contains_key(course_id))
+            })
+            .take(MAX_REWARD_COURSES)
+            .collect()
+    });
+
+    for (course_id, pending) in pools {
+        let stakes = course_stakes(&course_id);
+        let amounts: Vec<u64> = stakes.iter().map(|(_, stake)| stake.amount).collect();
+        let shares = pro_rata_shares(pending, &amounts);
+
+        CURATOR_PAYOUTS.with(|payouts| {
+            let mut payouts = payouts.borrow_mut();
+            for ((key, _), share) in stakes.into_iter().zip(shares) {
+                if share > 0 {
+                    payouts.insert(key, share);
+                }
+            }
+        });
+        CURATOR_POOLS.with(|pools| pools.borrow_mut().remove(&course_id));
+    }
+}
+
+fn active_hold(hold_id: u64) -> Result<Hold, TokenError> {
+    let hold = HOLDS
+        .with(|holds| holds.borrow().get(&hold_id))
+        .ok_or(TokenError::HoldNotFound { hold_id })?;
+    if hold.status != HoldStatus::Active {
+        return Err(TokenError::HoldNotActive { status: hold.status });
+    }
+    Ok(hold)
+}
+
+// Pays an active hold out of escrow, to the payee when released and back to
+// the payer when refunded.
+fn settle_hold(mut hold: Hold, outcome: HoldStatus, now: u64) -> Result<Hold, TokenError> {
+    let (to, transaction_type, verb) = match outcome {
+        HoldStatus::Released => (hold.payee, TransactionType::HoldRelease, "released"),
+        HoldStatus::Refunded => (hold.payer, TransactionType::HoldRefund, "refunded"),
+        HoldStatus::Active => return Err(TokenError::HoldNotActive { status: hold.status }),
+    };
+    // Escrowed funds stay put while either side is frozen.
+    ensure_not_frozen(hold.payer.owner)?;
+    ensure_not_frozen(hold.payee.owner)?;
+
+    move_balance(escrow_account(), to, hold.amount, 0, now).expect("escrow does not cover an active hold");
+
+    let block_index = record_transaction(TokenTransaction {
+        id: next_id("tx"),
+        from: escrow_account().owner,
+        to: to.owner,
+        amount: hold.amount,
+        transaction_type,
+        timestamp: now,
+        memo: format!("Hold {} {}", hold.id, verb),
+        from_subaccount: escrow_account().subaccount,
+        to_subaccount: to.subaccount,
+        spender: None,
+        fee: None,
+        batch_id: None,
+        fee_collector: None,
+        metadata: None,
+        raw_memo: None,
+    });
+
+    hold.status = outcome;
+    hold.resolved_at = Some(now);
+    hold.resolved_in_block = Some(block_index);
+    HOLDS.with(|holds| holds.borrow_mut().insert(hold.id, hold.clone()));
+    HOLD_EXPIRIES.with(|expiries| expiries.borrow_mut().remove(&(hold.expires_at, hold.id)));
+
+    Ok(hold)
+}
+
+// Makes sure a timer fires at the earliest hold expiry.
+fn schedule_hold_expiry(now: u64) {
+    let Some(next_expiry) = HOLD_EXPIRIES.with(|expiries| {
+        expiries
+            .borrow()
+            .keys()
+            .find(|(_, hold_id)| hold_can_settle(*hold_id))
+            .map(|(expires_at, _)| expires_at)
+    }) else {
+        return;
+    };
+
+    if let Some((deadline, timer_id)) = HOLD_TIMER.get() {
+        if deadline <= next_expiry {
+            return;
+        }
+        ic_cdk_timers::clear_timer(timer_id);
+    }
+
+    let delay = Duration::from_nanos(next_expiry.saturating_sub(now));
+    let timer_id = ic_cdk_timers::set_timer(delay, release_expired_holds);
+    HOLD_TIMER.set(Some((next_expiry, timer_id)));
+}
+
+// Holds with a frozen party wait in HOLD_EXPIRIES until it is unfrozen.
+fn hold_can_settle(hold_id: u64) -> bool {
+    HOLDS.with(|holds| holds.borrow().get(&hold_id)).is_some_and(|hold| {
+        ensure_not_frozen(hold.payer.owner).is_ok() && ensure_not_frozen(hold.payee.owner).is_ok()
+    })
+}
+
+fn release_expired_holds() {
+    HOLD_TIMER.set(None);
+    let now = time();
+
+    let expired: Vec<u64> = HOLD_EXPIRIES.with(|expiries| {
+        expiries
+            .borrow()
+            .range(..(now, u64::MAX))
+            .map(|((_, hold_id), _)| hold_id)
+            .filter(|hold_id| hold_can_settle(*hold_id))
+            .take(MAX_HOLD_RELEASES)
+            .collect()
+    });
+
+    for hold_id in expired {
+        if let Ok(hold) = active_hold(hold_id) {
+            if let Err(err) = settle_hold(hold, HoldStatus::Released, now) {
+                ic_cdk::print(format!("failed to release hold {}: {:?}", hold_id, err));
+            }
+        }
+    }
+
+    schedule_hold_expiry(now);
+}
+
+// Burns are free: the whole amount leaves circulation. Returns the block
+// index along with the recorded transaction.
+fn burn(
+    from: Account,
+    amount: u64,
+    memo: Vec<u8>,
+    burner: Option<Account>,
+    now: u64,
+) -> Result<(u64, TokenTransaction), TokenError> {
+    if amount < MIN_BURN_AMOUNT {
+        return Err(TokenError::BadBurn {
+            min_burn_amount: MIN_BURN_AMOUNT,
+        });
+    }
+
+    debit(from, amount, now).map_err(|balance| TokenError::InsufficientFunds { balance })?;
+    reduce_supply(amount);
+
+    let (memo, raw_memo) = split_memo(memo);
+    let transaction = TokenTransaction {
+        id: next_id("tx"),
+        from: from.owner,
+        to: minting_account().owner,
+        amount,
+        transaction_type: TransactionType::Burn,
+        timestamp: now,
+        memo,
+        from_subaccount: from.subaccount,
+        to_subaccount: None,
+        spender: burner,
+        fee: None,
+        batch_id: None,
+        fee_collector: None,
+        metadata: None,
+        raw_memo,
+    };
+    let block_index = record_transaction(transaction.clone());
+    Ok((block_index, transaction))
+}
+
+fn transfer_fee() -> u64 {
+    read_state(|state| state.transfer_fee.unwrap_or(DEFAULT_TRANSFER_FEE))
+}
+
+// Appends the transaction to the block log, certifies the new tip and
+// returns its block index.
+fn record_transaction(transaction: TokenTransaction) -> u64 {
+    let (index, hash) = append_block(transaction);
+    certify_tip(index, hash);
+    index
+}
+
+// Chains and indexes a block without certifying it as the new tip.
+fn append_block(transaction: TokenTransaction) -> (u64, Hash) {
+    BLOCKS.with(|blocks| {
+        let mut blocks_map = blocks.borrow_mut();
+        let (index, parent_hash) = match blocks_map.last_key_value() {
+            Some((last_index, last_block)) => (last_index + 1, Some(last_block.hash())),
+            None => (0, None),
+        };
+        let block = Block {
+            parent_hash,
+            transaction,
+        };
+        let hash = block.hash();
+        index_block(index, &block);
+        blocks_map.insert(index, block);
+        (index, hash)
+    })
+}
+
+fn index_block(block_index: u64, block: &Block) {
+    let tx = &block.transaction;
+    let mut accounts = vec![
+        Account {
+            owner: tx.from,
+            subaccount: tx.from_subaccount,
+        },
+        Account {
+            owner: tx.to,
+            subaccount: tx.to_subaccount,
+        },
+    ];
+    accounts.extend(tx.spender);
+    accounts.sort();
+    accounts.dedup();
+
+    ACCOUNT_TRANSACTIONS.with(|index| {
+        let mut index_map = index.borrow_mut();
+        for account in accounts {
+            index_map.insert((account, block_index), ());
+        }
+    });
+}
+
+// Builds the account index for blocks written before it existed.
+fn backfill_account_index() {
+    if ACCOUNT_TRANSACTIONS.with(|index| !index.borrow().is_empty()) {
+        return;
+    }
+    BLOCKS.with(|blocks| {
+        for (block_index, block) in blocks.borrow().iter() {
+            index_block(block_index, &block);
+        }
+    });
+}
+
+// Seeds a checkpoint for every balance that has none, i.e. one last changed
+// before checkpoints were recorded. It is keyed by the last block at or
+// before that change, or the tip when the block is not local. Nothing is
+// known about earlier history, so those accounts read as empty before it.
+fn backfill_balance_checkpoints() {
+    migrate_legacy_balance_checkpoints();
+    let Some(tip) = last_block_index() else {
+        return;
+    };
+    BALANCES.with(|balances| {
+        BALANCE_CHECKPOINTS.with(|checkpoints| {
+            let mut checkpoints_map = checkpoints.borrow_mut();
+            for (account, balance) in balances.borrow().iter() {
+                if checkpoints_map.range((account, 0)..=(account, u64::MAX)).next().is_some() {
+                    continue;
+                }
+                let checkpoint = BalanceCheckpoint {
+                    balance: balance.balance,
+                    changed_at: balance.updated_at,
+                };
+                let block_index = last_block_at(balance.updated_at).unwrap_or(tip);
+                checkpoints_map.insert((account, block_index), checkpoint);
+            }
+        })
+    });
+}
+
+// Re-keys time-keyed checkpoints by the last block at or before their time.
+// Changes from blocks that are already archived land on the block before
+// the first local one.
+fn migrate_legacy_balance_checkpoints() {
+    let legacy: Vec<((Account, u64), u64)> =
+        LEGACY_BALANCE_CHECKPOINTS.with(|checkpoints| checkpoints.borrow().iter().collect());
+    if legacy.is_empty() {
+        return;
+    }
+    let first_local = first_local_block();
+    BALANCE_CHECKPOINTS.with(|checkpoints| {
+        let mut checkpoints_map = checkpoints.borrow_mut();
+        for ((account, changed_at), balance) in legacy {
+            let block_index = last_block_at(changed_at).unwrap_or(first_local.saturating_sub(1));
+            checkpoints_map.insert((account, block_index), BalanceCheckpoint { balance, changed_at });
+        }
+    });
+    LEGACY_BALANCE_CHECKPOINTS.with(|checkpoints| checkpoints.borrow_mut().clear_new());
+}
+
+// Index of the last local block with a timestamp at or before `timestamp`.
+// Block times never decrease, so this is a binary search.
+fn last_block_at(timestamp: u64) -> Option<u64> {
+    BLOCKS.with(|blocks| {
+        let blocks_map = blocks.borrow();
+        let (mut low, mut high) = (first_local_block(), last_block_index()? + 1);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let block_time = blocks_map.get(&mid).map(|block| block.transaction.timestamp).unwrap_or(u64::MAX);
+            if block_time <= timestamp {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        (low > first_local_block()).then(|| low - 1)
+    })
+}
+
+fn last_block_index() -> Option<u64> {
+    BLOCKS.with(|blocks| blocks.borrow().last_key_value().map(|(index, _)| index))
+}
+
+fn last_block() -> Option<(u64, Hash)> {
+    BLOCKS.with(|blocks| {
+        blocks
+            .borrow()
+            .last_key_value()
+            .map(|(index, block)| (index, block.hash()))
+    })
+}
+
+fn block_hash(index: u64) -> Option<Hash> {
+    BLOCKS.with(|blocks| blocks.borrow().get(&index).map(|block| block.hash()))
+}
+
+fn tip_tree(index: u64, hash: Hash) -> HashTree {
+    let mut index_leb = vec![];
+    Nat::from(index)
+        .encode(&mut index_leb)
+        .expect("LEB128 encoding of a nat cannot fail");
+    fork(
+        label("last_block_hash", leaf(hash.to_vec())),
+        label("last_block_index", leaf(index_leb)),
+    )
+}
+
+fn certify_tip(index: u64, hash: Hash) {
+    set_certified_data(&tip_tree(index, hash).digest());
+}
+
+// Moves transactions recorded before the block log existed into BLOCKS,
+// oldest first, so they become part of the hash chain. The tip is certified
+// once the upgrade is done.
+fn migrate_legacy_transactions() {
+    let mut legacy: Vec<(String, TokenTransaction)> =
+        LEGACY_TRANSACTIONS.with(|transactions| transactions.borrow().iter().collect());
+    legacy.sort_by_key(|(_, tx)| tx.timestamp);
+
+    for (key, transaction) in legacy {
+        append_block(transaction);
+        LEGACY_TRANSACTIONS.with(|transactions| {
+            transactions.borrow_mut().remove(&key);
+        });
+    }
+}
+
+// Records a claim for every course reward paid before claims were kept, so
+// none of them can be claimed again. Vested rewards were minted to the
+// vesting account; their student is the beneficiary of the grant created in
+// the same block. Blocks already moved to the archive are not seen.
+fn backfill_reward_claims() {
+    if read_state(|state| state.reward_claims_backfilled.unwrap_or(false)) {
+        return;
+    }
+    let beneficiaries: BTreeMap<u64, Principal> = VESTING_GRANTS.with(|grants| {
+        grants.borrow().iter()
+            .map(|(_, grant)| (grant.created_in_block, grant.beneficiary.owner))
+            .collect()
+    });
+
+    BLOCKS.with(|blocks| {
+        REWARD_CLAIMS.with(|claims| {
+            let mut claims_map = claims.borrow_mut();
+            for (block_index, block) in blocks.borrow().iter() {
+                let tx = block.transaction;
+                if tx.transaction_type != TransactionType::CourseReward {
+                    continue;
+                }
+                let Some(course_id) = tx.memo.strip_prefix(COURSE_REWARD_MEMO_PREFIX) else {
+                    continue;
+                };
+                let student = beneficiaries.get(&block_index).copied().unwrap_or(tx.to);
+                let key = reward_claim_key(student, course_id);
+                if !claims_map.contains_key(&key) {
+                    claims_map.insert(key, RewardClaim {
+                        student,
+                        course_id: course_id.to_string(),
+                        amount: tx.amount,
+                        minter: tx.from,
+                        block_index,
+                        claimed_at: tx.timestamp,
+                    });
+                }
+            }
+        })
+    });
+    mutate_state(|state| state.reward_claims_backfilled = Some(true));
+}
+
+#[init]
+fn init(metadata: Option<TokenMetadata>) {
+    if let Some(metadata) = metadata {
+        if let Err(message) = validate_metadata(&metadata) {
+            ic_cdk::trap(&message);
+        }
+        METADATA.with(|cell| cell.borrow_mut().set(metadata).expect("failed to store token metadata"));
+    }
+    start_vesting_timer();
+    start_staking_timer();
+    start_archive_timer();
+    schedule_id_salt();
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    // All ledger state is already in stable memory. Remember the tip so
+    // post_upgrade can tell whether the new code still hashes the same chain.
+    let tip = last_block();
+    mutate_state(|state| state.tip_before_upgrade = tip);
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    migrate_stable_state();
+    if let Some((index, hash)) = last_block() {
+        certify_tip(index, hash);
+    }
+    check_consistency_after_upgrade();
+    schedule_hold_expiry(time());
+    start_vesting_timer();
+    start_staking_timer();
+    start_archive_timer();
+    abort_running_audit();
+    schedule_id_salt();
+}
+
+// Brings state written by older versions up to date. Legacy transactions
+// become blocks before balance checkpoints and reward claims are backfilled,
+// since both are read from the block log.
+fn migrate_stable_state() {
+    backfill_account_index();
+    migrate_legacy_transactions();
+    backfill_balance_checkpoints();
+    backfill_reward_claims();
+}
+
+// Recomputes the supply from BALANCES and corrects the stored value if it
+// drifted, e.g. when upgrading from a version that kept it on the heap.
+fn check_consistency_after_upgrade() {
+    let recomputed_supply = sum_of_balances();
+
+    mutate_state(|state| {
+        let check = UpgradeCheck {
+            checked_at: time(),
+            stored_supply: state.total_supply,
+            recomputed_supply,
+            // Blocks may have been appended after the old tip (legacy
+            // migration), so compare the old tip's position, not the new tip.
+            tip_intact: match state.tip_before_upgrade {
+                Some((index, hash)) => block_hash(index) == Some(hash),
+                None => true,
+            },
+        };
+
+        if check.stored_supply != check.recomputed_supply {
+            ic_cdk::print(format!(
+                "total supply mismatch after upgrade: stored {}, balances sum to {}",
+                check.stored_supply, check.recomputed_supply
+            ));
+            state.total_supply = recomputed_supply;
+        }
+        if !check.tip_intact {
+            ic_cdk::print("block log tip changed across upgrade");
+        }
+
+        state.tip_before_upgrade = None;
+        state.last_upgrade_check = Some(check);
     });
-    
-    if sender_balance < amount {
-        return Err("Insufficient balance".to_string());
+}
+
+#[query]
+pub fn get_last_upgrade_check() -> Option<UpgradeCheck> {
+    read_state(|state| state.last_upgrade_check.clone())
+}
+
+// Account statements
+
+// Serves `/statement?account=<principal>` as CSV (default) or, with
+// `format=json`, JSON. Optional parameters: `subaccount` (hex), `from` and
+// `to` (nanosecond timestamps, `to` exclusive), `start` (block index to
+// resume from) and `limit`. The next page's `start` is returned in the
+// `x-next-start` header, and in the body for JSON. Blocks that moved to an
+// archive are listed as `archived` rows in the CSV, and under `archived` in
+// JSON, so a page is never silently short.
+//
+// Responses are not certified, so statements are only served through the
+// raw domain (`<canister id>.raw.icp0.io`); the certified domain rejects
+// them.
+#[query]
+pub fn http_request(request: HttpRequest) -> HttpResponse {
+    let (path, query) = request.url.split_once('?').unwrap_or((&request.url, ""));
+    if request.method != "GET" {
+        return text_response(405, "Only GET is supported".to_string());
     }
-    
-    // Update balances
-    BALANCES.with(|balances| {
-        let mut balances_map = balances.borrow_mut();
-        
-        // Deduct from sender
-        let mut sender_balance = balances_map.get(&caller).unwrap();
-        sender_balance.balance -= amount;
-        sender_balance.updated_at = now;
-        balances_map.insert(caller, sender_balance);
-        
-        // Add to recipient
-        let mut recipient_balance = balances_map.get(&to).unwrap_or(TokenBalance {
-            owner: to,
-            balance: 0,
-            updated_at: now,
-        });
-        recipient_balance.balance += amount;
-        recipient_balance.updated_at = now;
-        balances_map.insert(to, recipient_balance);
+    if path != "/statement" {
+        return text_response(404, "Not found".to_string());
+    }
+
+    let params: BTreeMap<&str, &str> = query.split('&').filter_map(|pair| pair.split_once('=')).collect();
+    let statement = match parse_statement_request(&params) {
+        Ok((account, from, to, start, limit)) => account_statement(account, from, to, start, limit),
+        Err(message) => return text_response(400, message),
+    };
+
+    let mut headers = Vec::new();
+    if let Some(next) = statement.next {
+        headers.push(("x-next-start".to_string(), next.to_string()));
+    }
+    let archived_count: usize = statement.archived.iter().map(|a| a.block_indices.len()).sum();
+    if archived_count > 0 {
+        headers.push(("x-archived-blocks".to_string(), archived_count.to_string()));
+    }
+
+    let body = match params.get("format").copied().unwrap_or("csv") {
+        "csv" => {
+            headers.push(("content-type".to_string(), "text/csv; charset=utf-8".to_string()));
+            statement_csv(&statement)
+        }
+        "json" => {
+            headers.push(("content-type".to_string(), "application/json".to_string()));
+            serde_json::to_vec(&statement).expect("statement serializes to JSON")
+        }
+        _ => return text_response(400, "format must be csv or json".to_string()),
+    };
+
+    HttpResponse {
+        status_code: 200,
+        headers,
+        body,
+    }
+}
+
+type StatementRequest = (Account, u64, u64, u64, u64);
+
+fn parse_statement_request(params: &BTreeMap<&str, &str>) -> Result<StatementRequest, String> {
+    let number = |name: &str| -> Result<Option<u64>, String> {
+        params
+            .get(name)
+            .map(|value| value.parse::<u64>().map_err(|_| format!("{} must be a number", name)))
+            .transpose()
+    };
+
+    let owner = params.get("account").ok_or("account is required")?;
+    let owner = Principal::from_text(owner).map_err(|_| "account is not a valid principal".to_string())?;
+    let subaccount = params
+        .get("subaccount")
+        .map(|hex| parse_subaccount(hex).ok_or("subaccount must be 64 hex characters"))
+        .transpose()?;
+
+    let from = number("from")?.unwrap_or(0);
+    let to = number("to")?.unwrap_or(u64::MAX);
+    if from > to {
+        return Err("from must not be after to".to_string());
+    }
+    let start = number("start")?.unwrap_or(0);
+    let limit = number("limit")?
+        .unwrap_or(DEFAULT_STATEMENT_PAGE_SIZE)
+        .clamp(1, MAX_STATEMENT_PAGE_SIZE);
+
+    Ok((Account { owner, subaccount }, from, to, start, limit))
+}
+
+fn account_statement(account: Account, from: u64, to: u64, start: u64, limit: u64) -> Statement {
+    let first_local = first_local_block();
+    let mut entries = Vec::new();
+    let mut archived: Vec<ArchivedTransactions> = Vec::new();
+    let mut next = None;
+    let mut scanned = 0;
+
+    ACCOUNT_TRANSACTIONS.with(|index| {
+        for ((_, block_index), _) in index.borrow().range((account, start)..=(account, u64::MAX)) {
+            let returned = entries.len() + archived.iter().map(|a| a.block_indices.len()).sum::<usize>();
+            if returned as u64 >= limit || scanned >= MAX_HISTORY_SCAN {
+                next = Some(block_index);
+                break;
+            }
+            scanned += 1;
+
+            if block_index < first_local {
+                push_archived(&mut archived, block_index);
+                continue;
+            }
+            let Some(block) = BLOCKS.with(|blocks| blocks.borrow().get(&block_index)) else {
+                continue;
+            };
+            let tx = block.transaction;
+            // Blocks are appended in time order, so nothing later can fall
+            // inside the range.
+            if tx.timestamp >= to {
+                break;
+            }
+            if tx.timestamp < from {
+                continue;
+            }
+            entries.extend(statement_entry(account, block_index, tx));
+        }
     });
-    
-    // Record transaction
-    let transaction = TokenTransaction {
-        id: transaction_id.clone(),
-        from: caller,
+
+    Statement {
+        account: account.owner,
+        subaccount: account.subaccount.map(|subaccount| hex_string(&subaccount)),
+        from,
         to,
-        amount,
-        transaction_type: TransactionType::PeerTip,
-        timestamp: now,
-        memo,
+        entries,
+        archived,
+        next,
+    }
+}
+
+// Rows only show what moved the account's own balance. Blocks where it was
+// just the spender are left out, and an approval shows only the fee its
+// owner paid, not the approved amount.
+fn statement_entry(account: Account, block_index: u64, tx: TokenTransaction) -> Option<StatementEntry> {
+    let from = Account {
+        owner: tx.from,
+        subaccount: tx.from_subaccount,
     };
-    
-    TRANSACTIONS.with(|transactions| {
-        transactions.borrow_mut().insert(transaction_id, transaction.clone());
-    });
-    
-    Ok(transaction)
+    let to = Account {
+        owner: tx.to,
+        subaccount: tx.to_subaccount,
+    };
+    let approve = tx.transaction_type == TransactionType::Approve;
+    let (direction, counterparty) = match (from == account, to == account) {
+        (true, true) => ("self", to),
+        (true, false) => ("out", to),
+        (false, true) if !approve => ("in", from),
+        _ => return None,
+    };
+
+    Some(StatementEntry {
+        block_index,
+        transaction_id: tx.id,
+        timestamp: tx.timestamp,
+        kind: tx.transaction_type.name().to_string(),
+        direction: direction.to_string(),
+        counterparty: counterparty.owner,
+        counterparty_subaccount: counterparty.subaccount.map(|subaccount| hex_string(&subaccount)),
+        amount: if approve { 0 } else { tx.amount },
+        fee: if from == account { tx.fee.unwrap_or(0) } else { 0 },
+        memo: tx.memo,
+        batch_id: tx.batch_id,
+    })
+}
+
+fn statement_csv(statement: &Statement) -> Vec<u8> {
+    let mut csv = String::from(
+        "block_index,transaction_id,timestamp,kind,direction,counterparty,counterparty_subaccount,amount,fee,memo,batch_id\r\n",
+    );
+    // Archived blocks precede every local one. Their details are only known
+    // to the archive, named in the counterparty column.
+    for archive in &statement.archived {
+        for block_index in &archive.block_indices {
+            csv.push_str(&format!("{},,,archived,,{},,,,,\r\n", block_index, archive.canister_id.to_text()));
+        }
+    }
+    for entry in &statement.entries {
+        let row = [
+            entry.block_index.to_string(),
+            csv_field(&entry.transaction_id),
+            entry.timestamp.to_string(),
+            entry.kind.clone(),
+            entry.direction.clone(),
+            entry.counterparty.to_text(),
+            entry.counterparty_subaccount.clone().unwrap_or_default(),
+            entry.amount.to_string(),
+            entry.fee.to_string(),
+            csv_field(&entry.memo),
+            csv_field(entry.batch_id.as_deref().unwrap_or_default()),
+        ];
+        csv.push_str(&row.join(","));
+        csv.push_str("\r\n");
+    }
+    csv.into_bytes()
+}
+
+// Quotes fields that need it, and defuses ones a spreadsheet would read as
+// a formula.
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+fn text_response(status_code: u16, message: String) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![("content-type".to_string(), "text/plain; charset=utf-8".to_string())],
+        body: message.into_bytes(),
+    }
+}
+
+fn parse_subaccount(hex: &str) -> Option<Subaccount> {
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut subaccount = [0; 32];
+    for (i, byte) in subaccount.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(subaccount)
+}
+
+fn hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Block archiving
+
+// Points archiving at a new archive canister, which must have been
+// installed with this ledger's id. Blocks already archived stay where they
+// are.
+#[update]
+pub fn set_archive_config(config: ArchiveConfig) -> Result<ArchiveConfig, TokenError> {
+    ensure_controller(ic_cdk::caller())?;
+    // The newest block must stay local to chain the next one onto.
+    if config.num_blocks_to_archive == 0 || config.num_blocks_to_archive >= config.trigger_threshold {
+        return Err(TokenError::GenericError {
+            message: "Blocks to archive must be at least 1 and below the trigger threshold".to_string(),
+        });
+    }
+    mutate_state(|state| state.archive_config = Some(config.clone()));
+    Ok(config)
 }
 
 #[query]
-pub fn get_balance(principal: Option<Principal>) -> u64 {
-    let target_principal = principal.unwrap_or(ic_cdk::caller());
-    
-    BALANCES.with(|balances| {
-        balances.borrow().get(&target_principal).map(|b| b.balance).unwrap_or(0)
+pub fn get_archive_config() -> ArchiveConfig {
+    archive_config()
+}
+
+fn archive_config() -> ArchiveConfig {
+    read_state(|state| state.archive_config.clone()).unwrap_or(ArchiveConfig {
+        archive_canister: None,
+        trigger_threshold: DEFAULT_ARCHIVE_TRIGGER_THRESHOLD,
+        num_blocks_to_archive: DEFAULT_NUM_BLOCKS_TO_ARCHIVE,
     })
 }
 
-#[query]
-pub fn get_transaction_history(principal: Option<Principal>) -> Vec<TokenTransaction> {
-    let target_principal = principal.unwrap_or(ic_cdk::caller());
-    
-    TRANSACTIONS.with(|transactions| {
-        transactions.borrow().iter()
-            .filter(|(_, tx)| tx.from == target_principal || tx.to == target_principal)
-            .map(|(_, tx)| tx)
-            .collect()
+// Index of the oldest block still held by the ledger.
+fn first_local_block() -> u64 {
+    BLOCKS.with(|blocks| blocks.borrow().first_key_value().map(|(index, _)| index).unwrap_or(0))
+}
+
+fn archive_for(block_index: u64) -> Option<ArchiveRange> {
+    ARCHIVES.with(|archives| {
+        archives
+            .borrow()
+            .range(..=block_index)
+            .next_back()
+            .map(|(_, range)| range)
+            .filter(|range| block_index <= range.end)
     })
 }
 
+// Adds an archived block to `archived`, grouped by archive canister.
+fn push_archived(archived: &mut Vec<ArchivedTransactions>, block_index: u64) {
+    let Some(range) = archive_for(block_index) else {
+        return;
+    };
+    match archived.last_mut() {
+        Some(last) if last.canister_id == range.canister_id => last.block_indices.push(block_index),
+        _ => archived.push(ArchivedTransactions {
+            canister_id: range.canister_id,
+            block_indices: vec![block_index],
+        }),
+    }
+}
+
+fn start_archive_timer() {
+    ic_cdk_timers::set_timer_interval(ARCHIVE_INTERVAL, || ic_cdk::spawn(archive_blocks()));
+}
+
+// Moves the oldest blocks to the archive once the local log grows past the
+// trigger threshold. Blocks are only removed once the archive has stored
+// them, so a failed call is retried on the next tick.
+async fn archive_blocks() {
+    let config = archive_config();
+    let Some(archive) = config.archive_canister else {
+        return;
+    };
+    if ARCHIVING.get() || BLOCKS.with(|blocks| blocks.borrow().len()) <= config.trigger_threshold {
+        return;
+    }
+    ARCHIVING.set(true);
+
+    let mut remaining = config.num_blocks_to_archive;
+    while remaining > 0 {
+        let batch: Vec<ArchivedBlock> = BLOCKS.with(|blocks| {
+            blocks
+                .borrow()
+                .iter()
+                .take(remaining.min(MAX_ARCHIVE_BATCH) as usize)
+                .map(|(id, block)| ArchivedBlock {
+                    id,
+                    value: block.to_value(),
+                    block,
+                })
+                .collect()
+        });
+        let (Some(first), Some(last)) = (batch.first().map(|b| b.id), batch.last().map(|b| b.id)) else {
+            break;
+        };
+
+        let result: Result<(Result<u64, String>,), _> = ic_cdk::call(archive, "append_blocks", (batch,)).await;
+        match result {
+            Ok((Ok(_),)) => {}
+            Ok((Err(message),)) => {
+                ic_cdk::print(format!("archive rejected blocks {}..={}: {}", first, last, message));
+                break;
+            }
+            Err((code, message)) => {
+                ic_cdk::print(format!("failed to archive blocks {}..={}: {:?} {}", first, last, code, message));
+                break;
+            }
+        }
+
+        BLOCKS.with(|blocks| {
+            let mut blocks_map = blocks.borrow_mut();
+            for id in first..=last {
+                blocks_map.remove(&id);
+            }
+        });
+        record_archived_range(archive, first, last);
+        remaining = remaining.saturating_sub(last - first + 1);
+    }
+
+    ARCHIVING.set(false);
+}
+
+// Extends the newest range when the blocks went to the same archive right
+// after it.
+fn record_archived_range(canister_id: Principal, start: u64, end: u64) {
+    ARCHIVES.with(|archives| {
+        let mut archives_map = archives.borrow_mut();
+        let range = match archives_map.last_key_value() {
+            Some((_, last)) if last.canister_id == canister_id && last.end + 1 == start => ArchiveRange { end, ..last },
+            _ => ArchiveRange {
+                canister_id,
+                start,
+                end,
+            },
+        };
+        archives_map.insert(range.start, range);
+    });
+}
+
+// Ledger audit
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AuditPhase {
+    // Replaying blocks into `replayed`.
+    Replay,
+    // Comparing BALANCES with the replay, resuming after the account.
+    CompareStored(Option<Account>),
+    // Looking for replayed accounts missing from BALANCES.
+    CompareReplayed(Option<Account>),
+}
+
+struct AuditProgress {
+    report: AuditReport,
+    phase: AuditPhase,
+    next_block: u64,
+    last_hash: Option<Hash>,
+    fee_collector: Option<Account>,
+    replayed: BTreeMap<Account, u64>,
+}
+
+impl AuditProgress {
+    fn credit(&mut self, account: Account, amount: u64) {
+        let balance = self.replayed.entry(account).or_insert(0);
+        *balance = balance.saturating_add(amount);
+    }
+
+    fn debit(&mut self, block_index: u64, account: Account, amount: u64) {
+        let balance = self.replayed.entry(account).or_insert(0);
+        match balance.checked_sub(amount) {
+            Some(remaining) => *balance = remaining,
+            None => {
+                *balance = 0;
+                self.block_issue(block_index, format!("debits {} more than the account held", amount));
+            }
+        }
+    }
+
+    fn block_issue(&mut self, block_index: u64, issue: String) {
+        self.report.block_issue_count += 1;
+        if self.report.block_issues.len() < MAX_AUDIT_FINDINGS {
+            self.report.block_issues.push(BlockIssue { block_index, issue });
+        }
+    }
+
+    fn discrepancy(&mut self, account: Account, stored_balance: u64, replayed_balance: u64) {
+        self.report.discrepancy_count += 1;
+        if self.report.discrepancies.len() < MAX_AUDIT_FINDINGS {
+            self.report.discrepancies.push(AccountDiscrepancy {
+                account,
+                stored_balance,
+                replayed_balance,
+            });
+        }
+    }
+
+    fn replay(&mut self, block_index: u64, block: &Block) {
+        if block.parent_hash != self.last_hash {
+            self.block_issue(block_index, "parent hash does not match the previous block".to_string());
+        }
+        self.last_hash = Some(block.hash());
+
+        let tx = &block.transaction;
+        let from = Account {
+            owner: tx.from,
+            subaccount: tx.from_subaccount,
+        };
+        let to = Account {
+            owner: tx.to,
+            subaccount: tx.to_subaccount,
+        };
+        let fee = tx.fee.unwrap_or(0);
+        let supply = self.report.replayed_supply;
+
+        match tx.transaction_type {
+            TransactionType::Mint | TransactionType::CourseReward => {
+                self.credit(to, tx.amount);
+                self.report.replayed_supply = supply.saturating_add(tx.amount);
+            }
+            TransactionType::Burn => {
+                self.debit(block_index, from, tx.amount);
+                self.report.replayed_supply = supply.saturating_sub(tx.amount);
+            }
+            TransactionType::AddMinter | TransactionType::RemoveMinter | TransactionType::SetMetadata => {}
+            TransactionType::SetFeeCollector => {
+                self.fee_collector = (to != minting_account()).then_some(to);
+            }
+            TransactionType::Approve => self.debit(block_index, from, fee),
+            _ => {
+                self.debit(block_index, from, tx.amount.saturating_add(fee));
+                self.credit(to, tx.amount);
+            }
+        }
+
+        // Blocks from before the collector was recorded on them fall back to
+        // the last logged SetFeeCollector.
+        if fee > 0 && tx.transaction_type != TransactionType::Burn {
+            match tx.fee_collector.or(self.fee_collector) {
+                Some(collector) => self.credit(collector, fee),
+                None => self.report.replayed_supply = self.report.replayed_supply.saturating_sub(fee),
+            }
+        }
+        self.report.blocks_replayed += 1;
+        self.next_block = block_index + 1;
+    }
+
+    // Replays blocks appended since the last step. Returns false when the
+    // instruction budget ran out first.
+    fn catch_up(&mut self) -> bool {
+        let next_block = self.next_block;
+        BLOCKS.with(|blocks| {
+            for (block_index, block) in blocks.borrow().range(next_block..) {
+                if ic_cdk::api::instruction_counter() > AUDIT_INSTRUCTION_BUDGET {
+                    return false;
+                }
+                self.replay(block_index, &block);
+            }
+            true
+        })
+    }
+
+    // Advances the audit by roughly one instruction budget. Returns true
+    // once the report is complete.
+    fn step(&mut self) -> bool {
+        // Blocks appended while the audit runs change BALANCES and the
+        // replay alike, so replaying them first keeps earlier comparisons
+        // valid.
+        if !self.catch_up() {
+            return false;
+        }
+
+        loop {
+            match self.phase {
+                AuditPhase::Replay => self.phase = AuditPhase::CompareStored(None),
+                AuditPhase::CompareStored(after) => {
+                    let page: Vec<(Account, TokenBalance)> = BALANCES.with(|balances| {
+                        let balances = balances.borrow();
+                        match after {
+                            Some(account) => balances
+                                .range((Excluded(account), Unbounded))
+                                .take(AUDIT_PAGE_SIZE)
+                                .collect(),
+                            None => balances.iter().take(AUDIT_PAGE_SIZE).collect(),
+                        }
+                    });
+                    for (account, balance) in &page {
+                        let replayed = self.replayed.get(account).copied().unwrap_or(0);
+                        if balance.balance != replayed {
+                            self.discrepancy(*account, balance.balance, replayed);
+                        }
+                        self.report.accounts_checked += 1;
+                    }
+                    self.phase = match page.last() {
+                        Some((account, _)) if page.len() == AUDIT_PAGE_SIZE => AuditPhase::CompareStored(Some(*account)),
+                        _ => AuditPhase::CompareReplayed(None),
+                    };
+                }
+                AuditPhase::CompareReplayed(after) => {
+                    let page: Vec<(Account, u64)> = match after {
+                        Some(account) => self
+                            .replayed
+                            .range((Excluded(account), Unbounded))
+                            .take(AUDIT_PAGE_SIZE)
+                            .map(|(account, balance)| (*account, *balance))
+                            .collect(),
+                        None => self
+                            .replayed
+                            .iter()
+                            .take(AUDIT_PAGE_SIZE)
+                            .map(|(account, balance)| (*account, *balance))
+                            .collect(),
+                    };
+                    for (account, replayed) in &page {
+                        let stored = BALANCES.with(|balances| balances.borrow().contains_key(account));
+                        if !stored && *replayed > 0 {
+                            self.discrepancy(*account, 0, *replayed);
+                        }
+                    }
+                    match page.last() {
+                        Some((account, _)) if page.len() == AUDIT_PAGE_SIZE => {
+                            self.phase = AuditPhase::CompareReplayed(Some(*account));
+                        }
+                        _ => {
+                            self.finish();
+                            return true;
+                        }
+                    }
+                }
+            }
+
+            if ic_cdk::api::instruction_counter() > AUDIT_INSTRUCTION_BUDGET {
+                return false;
+            }
+        }
+    }
+
+    // With every account matching the replay, the stored supply matching
+    // the replayed one also means BALANCES sums to the total supply.
+    fn finish(&mut self) {
+        let report = &mut self.report;
+        report.stored_supply = read_state(|state| state.total_supply);
+        report.finished_at = Some(time());
+        report.status = if report.discrepancy_count == 0
+            && report.block_issue_count == 0
+            && report.stored_supply == report.replayed_supply
+        {
+            AuditStatus::Passed
+        } else {
+            AuditStatus::Failed
+        };
+    }
+}
+
+// Starts replaying the block log in the background. Poll
+// `get_audit_report` for the outcome.
+#[update]
+pub fn audit_ledger() -> Result<AuditReport, TokenError> {
+    let caller = ic_cdk::caller();
+    ensure_controller(caller)?;
+    if AUDIT.with(|audit| audit.borrow().is_some()) {
+        return Err(TokenError::GenericError {
+            message: "An audit is already running".to_string(),
+        });
+    }
+
+    let report = AuditReport {
+        id: next_sequence("audit"),
+        started_by: caller,
+        started_at: time(),
+        finished_at: None,
+        status: AuditStatus::Running,
+        blocks_replayed: 0,
+        accounts_checked: 0,
+        stored_supply: read_state(|state| state.total_supply),
+        replayed_supply: 0,
+        discrepancy_count: 0,
+        discrepancies: Vec::new(),
+        block_issue_count: 0,
+        block_issues: Vec::new(),
+    };
+    AUDIT_REPORTS.with(|reports| reports.borrow_mut().insert(report.id, report.clone()));
+    AUDIT.with(|audit| {
+        *audit.borrow_mut() = Some(AuditProgress {
+            report: report.clone(),
+            phase: AuditPhase::Replay,
+            next_block: 0,
+            last_hash: None,
+            // Fees were burned until a collector was first logged.
+            fee_collector: None,
+            replayed: BTreeMap::new(),
+        })
+    });
+    ic_cdk_timers::set_timer(Duration::ZERO, run_audit_step);
+
+    Ok(report)
+}
+
+// The given audit's report, or the latest one.
 #[query]
-pub fn get_total_supply() -> u64 {
-    TOTAL_SUPPLY.with(|supply| *supply.borrow())
-}
\ No newline at end of file
+pub fn get_audit_report(audit_id: Option<u64>) -> Option<AuditReport> {
+    AUDIT_REPORTS.with(|reports| {
+        let reports = reports.borrow();
+        match audit_id {
+            Some(audit_id) => reports.get(&audit_id),
+            None => reports.last_key_value().map(|(_, report)| report),
+        }
+    })
+}
+
+fn run_audit_step() {
+    let next_block = AUDIT.with(|audit| audit.borrow().as_ref().map(|progress| progress.next_block));
+    match next_block {
+        None => {}
+        Some(next_block) if next_block < first_local_block() => ic_cdk::spawn(replay_archived_blocks(next_block)),
+        Some(_) => advance_audit(AuditProgress::step),
+    }
+}
+
+// Fetches the next archived blocks and replays them. An archive that
+// cannot produce them fails the audit rather than skipping blocks.
+async fn replay_archived_blocks(start: u64) {
+    let fetched = match archive_for(start) {
+        Some(range) => {
+            let length = (range.end - start + 1).min(MAX_BLOCKS_PER_REQUEST);
+            ic_cdk::call::<_, (Vec<ArchivedBlock>,)>(range.canister_id, "get_blocks", (start, length))
+                .await
+                .map(|(blocks,)| blocks)
+                .map_err(|(code, message)| format!("{:?} {}", code, message))
+        }
+        None => Err("no archive holds it".to_string()),
+    };
+
+    advance_audit(|progress| {
+        let issue = match fetched {
+            Ok(blocks) if !blocks.is_empty() => {
+                for archived in blocks {
+                    progress.replay(archived.id, &archived.block);
+                }
+                return false;
+            }
+            Ok(_) => "archive returned no blocks".to_string(),
+            Err(message) => message,
+        };
+        progress.block_issue(start, format!("could not fetch archived block: {}", issue));
+        progress.finish();
+        true
+    });
+}
+
+// Runs `f` on the audit in progress, saves its report and schedules the
+// next step until `f` reports the audit finished.
+fn advance_audit(f: impl FnOnce(&mut AuditProgress) -> bool) {
+    let (report, finished) = AUDIT.with(|audit| {
+        let mut audit = audit.borrow_mut();
+        let Some(progress) = audit.as_mut() else {
+            return (None, true);
+        };
+        let finished = f(progress);
+        let report = progress.report.clone();
+        if finished {
+            *audit = None;
+        }
+        (Some(report), finished)
+    });
+
+    if let Some(report) = report {
+        AUDIT_REPORTS.with(|reports| reports.borrow_mut().insert(report.id, report));
+    }
+    if !finished {
+        ic_cdk_timers::set_timer(Duration::ZERO, run_audit_step);
+    }
+}
+
+// An upgrade drops the audit's progress and timer; mark its report so it
+// does not look like it is still running.
+fn abort_running_audit() {
+    AUDIT_REPORTS.with(|reports| {
+        let mut reports = reports.borrow_mut();
+        if let Some((id, mut report)) = reports.last_key_value() {
+            if report.status == AuditStatus::Running {
+                report.status = AuditStatus::Aborted;
+                report.finished_at = Some(time());
+                reports.insert(id, report);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(byte: u8) -> Account {
+        Account::new(Principal::from_slice(&[byte; 29]))
+    }
+
+    fn transaction(transaction_type: TransactionType, from: Account, to: Account, amount: u64) -> TokenTransaction {
+        TokenTransaction {
+            id: "tx_0".to_string(),
+            from: from.owner,
+            to: to.owner,
+            amount,
+            transaction_type,
+            timestamp: 1,
+            memo: String::new(),
+            from_subaccount: from.subaccount,
+            to_subaccount: to.subaccount,
+            spender: None,
+            fee: Some(10),
+            batch_id: None,
+            fee_collector: None,
+            metadata: None,
+            raw_memo: None,
+        }
+    }
+
+    fn dedup_key(caller: Principal, amount: u64, memo: &[u8], created_at_time: u64) -> DedupKey<'_> {
+        DedupKey {
+            caller,
+            transaction_type: &TransactionType::Transfer,
+            from: None,
+            to: Some(account(2)),
+            amount,
+            fee: None,
+            memo,
+            created_at_time,
+        }
+    }
+
+    const HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+    #[test]
+    fn retried_requests_are_duplicates() {
+        let caller = account(1).owner;
+        let now = 10 * HOUR;
+        let dedup = deduplicate(Some(dedup_key(caller, 5, b"rent", now)), now).ok().flatten();
+        assert!(dedup.is_some());
+        remember_transaction(dedup, 7, now);
+
+        assert!(matches!(
+            deduplicate(Some(dedup_key(caller, 5, b"rent", now)), now + 1),
+            Err(DedupError::Duplicate { duplicate_of: 7 })
+        ));
+        // Any field that differs makes it a new request.
+        assert!(deduplicate(Some(dedup_key(caller, 6, b"rent", now)), now).is_ok());
+        assert!(deduplicate(Some(dedup_key(caller, 5, b"food", now)), now).is_ok());
+        assert!(deduplicate(Some(dedup_key(caller, 5, b"rent", now + 1)), now).is_ok());
+        assert!(deduplicate(Some(dedup_key(account(3).owner, 5, b"rent", now)), now).is_ok());
+        assert!(matches!(deduplicate(None, now), Ok(None)));
+    }
+
+    #[test]
+    fn binary_memos_are_told_apart() {
+        let caller = account(1).owner;
+        let now = 10 * HOUR;
+        // Both decode to the same lossy text.
+        let (first, second): (&[u8], &[u8]) = (&[0xff, 0x01], &[0xfe, 0x01]);
+        assert_eq!(String::from_utf8_lossy(first), String::from_utf8_lossy(second));
+
+        let dedup = deduplicate(Some(dedup_key(caller, 5, first, now)), now).ok().flatten();
+        remember_transaction(dedup, 3, now);
+        assert!(matches!(
+            deduplicate(Some(dedup_key(caller, 5, first, now)), now),
+            Err(DedupError::Duplicate { duplicate_of: 3 })
+        ));
+        assert!(matches!(deduplicate(Some(dedup_key(caller, 5, second, now)), now), Ok(Some(_))));
+    }
+
+    #[test]
+    fn requests_outside_the_window_are_rejected() {
+        let caller = account(1).owner;
+        let now = 100 * HOUR;
+        let window = DEFAULT_DEDUP_WINDOW_NANOS;
+        assert!(matches!(
+            deduplicate(Some(dedup_key(caller, 1, b"", now - window - 1)), now),
+            Err(DedupError::TooOld)
+        ));
+        assert!(deduplicate(Some(dedup_key(caller, 1, b"", now - window)), now).is_ok());
+        assert!(matches!(
+            deduplicate(Some(dedup_key(caller, 1, b"", now + PERMITTED_DRIFT_NANOS + 1)), now),
+            Err(DedupError::CreatedInFuture { ledger_time }) if ledger_time == now
+        ));
+    }
+
+    #[test]
+    fn expired_requests_are_forgotten() {
+        let caller = account(1).owner;
+        let now = 100 * HOUR;
+        let dedup = deduplicate(Some(dedup_key(caller, 1, b"old", now)), now).ok().flatten();
+        remember_transaction(dedup, 0, now);
+
+        let later = now + DEFAULT_DEDUP_WINDOW_NANOS + PERMITTED_DRIFT_NANOS + 1;
+        prune_recent_transactions(later);
+        assert!(RECENT_TRANSACTIONS.with(|recent| recent.borrow().is_empty()));
+        assert!(RECENT_TRANSACTIONS_BY_TIME.with(|by_time| by_time.borrow().is_empty()));
+    }
+
+    fn grant(total: u64, start: u64, cliff_at: u64, end: u64) -> VestingGrant {
+        VestingGrant {
+            id: 0,
+            beneficiary: account(1),
+            total,
+            released: 0,
+            start,
+            cliff_at,
+            end,
+            memo: String::new(),
+            created_in_block: 0,
+        }
+    }
+
+    #[test]
+    fn nothing_vests_before_the_cliff() {
+        let grant = grant(1_000, 100, 150, 200);
+        assert_eq!(grant.vested(100), 0);
+        assert_eq!(grant.vested(149), 0);
+        // At the cliff everything since the start vests at once.
+        assert_eq!(grant.vested(150), 500);
+    }
+
+    #[test]
+    fn grants_vest_linearly_until_the_end() {
+        let grant = grant(1_000, 100, 100, 400);
+        assert_eq!(grant.vested(100), 0);
+        assert_eq!(grant.vested(200), 333);
+        assert_eq!(grant.vested(399), 996);
+        assert_eq!(grant.vested(400), 1_000);
+        assert_eq!(grant.vested(u64::MAX), 1_000);
+    }
+
+    #[test]
+    fn large_grants_do_not_overflow() {
+        let grant = grant(u64::MAX, 0, 0, u64::MAX);
+        assert_eq!(grant.vested(u64::MAX / 2), u64::MAX / 2);
+    }
+
+    #[test]
+    fn releases_leave_the_unvested_part_locked() {
+        let mut grant = grant(1_000, 0, 0, 100);
+        grant.released = grant.vested(25);
+        assert_eq!(grant.locked(), 750);
+        assert_eq!(grant.vested(60) - grant.released, 350);
+        grant.released = grant.vested(100);
+        assert_eq!(grant.locked(), 0);
+    }
+
+    #[test]
+    fn curator_shares_are_pro_rata_and_add_up() {
+        assert_eq!(pro_rata_shares(100, &[1, 1, 2]), vec![25, 25, 50]);
+        // 100 / 3 leaves one token over, which goes to the largest stake.
+        assert_eq!(pro_rata_shares(100, &[10, 10, 10]), vec![33, 33, 34]);
+        assert_eq!(pro_rata_shares(7, &[1, 5]), vec![1, 6]);
+        assert_eq!(pro_rata_shares(u64::MAX, &[u64::MAX, u64::MAX - 1]), vec![u64::MAX / 2 + 1, u64::MAX / 2]);
+        assert_eq!(pro_rata_shares(5, &[0, 0]), vec![0, 0]);
+        assert!(pro_rata_shares(5, &[]).is_empty());
+    }
+
+    #[test]
+    fn pools_are_split_once_and_queued_for_payout() {
+        for byte in 1..=3 {
+            let staker = account(byte);
+            let key = StakeKey {
+                course_id: "course_1".to_string(),
+                staker,
+            };
+            let stake = Stake {
+                course_id: "course_1".to_string(),
+                staker,
+                amount: 10,
+                rewards_earned: 0,
+                staked_at: 0,
+                updated_at: 0,
+                withheld_rewards: None,
+            };
+            STAKES.with(|stakes| stakes.borrow_mut().insert(key, stake));
+            add_course_stake("course_1", 10);
+        }
+        CURATOR_POOLS.with(|pools| pools.borrow_mut().insert("course_1".to_string(), 100));
+
+        queue_curator_payouts();
+        let queued: Vec<u64> = CURATOR_PAYOUTS.with(|payouts| payouts.borrow().iter().map(|(_, share)| share).collect());
+        assert_eq!(queued, vec![33, 33, 34]);
+        assert!(CURATOR_POOLS.with(|pools| pools.borrow().get(&"course_1".to_string())).is_none());
+
+        // An emptied pool is not split again.
+        queue_curator_payouts();
+        assert_eq!(CURATOR_PAYOUTS.with(|payouts| payouts.borrow().len()), 3);
+    }
+
+    #[test]
+    fn curator_share_is_taken_in_basis_points() {
+        assert_eq!(basis_points(1_000, 0), 0);
+        assert_eq!(basis_points(1_000, 500), 50);
+        assert_eq!(basis_points(999, 500), 49);
+        assert_eq!(basis_points(u64::MAX, 10_000), u64::MAX);
+    }
+
+    #[test]
+    fn the_mint_window_covers_the_last_day_of_buckets() {
+        assert_eq!(mint_window_start(0), 0);
+        assert_eq!(mint_window_start(23 * HOUR), 0);
+        assert_eq!(mint_window_start(24 * HOUR), 1);
+        assert_eq!(mint_window_start(30 * HOUR + 1), 7);
+    }
+
+    #[test]
+    fn mints_count_until_their_bucket_leaves_the_window() {
+        let minter = account(1).owner;
+        record_emission(minter, 100, HOUR / 2);
+        record_emission(minter, 50, 5 * HOUR);
+
+        assert_eq!(mint_quota(minter, 23 * HOUR).minter_minted, 150);
+        assert_eq!(mint_quota(minter, 24 * HOUR).minter_minted, 50);
+        assert_eq!(mint_quota(minter, 24 * HOUR).global_minted, 50);
+        assert_eq!(mint_quota(account(2).owner, 24 * HOUR).minter_minted, 0);
+
+        // Recording a later mint drops the expired bucket.
+        record_emission(minter, 1, 24 * HOUR);
+        assert!(MINTER_EMISSIONS.with(|emissions| !emissions.borrow().contains_key(&(minter, 0))));
+        assert!(GLOBAL_EMISSIONS.with(|emissions| !emissions.borrow().contains_key(&0)));
+    }
+
+    #[test]
+    fn mints_are_held_to_the_tighter_cap() {
+        let (first, second) = (account(1).owner, account(2).owner);
+        mutate_state(|state| {
+            state.mint_caps = Some(MintCaps {
+                minter_daily_cap: 100,
+                global_daily_cap: 150,
+            })
+        });
+
+        record_emission(first, 90, 0);
+        assert!(matches!(
+            check_mint_quota(first, 11, HOUR),
+            Err(TokenError::MintCapExceeded { remaining: 10 })
+        ));
+        assert!(check_mint_quota(first, 10, HOUR).is_ok());
+
+        record_emission(second, 50, 0);
+        assert!(matches!(
+            check_mint_quota(second, 11, HOUR),
+            Err(TokenError::MintCapExceeded { remaining: 10 })
+        ));
+    }
+
+    fn audit() -> AuditProgress {
+        AuditProgress {
+            report: AuditReport {
+                id: 0,
+                started_by: account(0).owner,
+                started_at: 0,
+                finished_at: None,
+                status: AuditStatus::Running,
+                blocks_replayed: 0,
+                accounts_checked: 0,
+                stored_supply: 0,
+                replayed_supply: 0,
+                discrepancy_count: 0,
+                discrepancies: Vec::new(),
+                block_issue_count: 0,
+                block_issues: Vec::new(),
+            },
+            phase: AuditPhase::Replay,
+            next_block: 0,
+            last_hash: None,
+            fee_collector: None,
+            replayed: BTreeMap::new(),
+        }
+    }
+
+    // Replays the transactions as a correctly chained log.
+    fn replay_all(audit: &mut AuditProgress, transactions: Vec<TokenTransaction>) {
+        for (index, transaction) in transactions.into_iter().enumerate() {
+            let block = Block {
+                parent_hash: audit.last_hash,
+                transaction,
+            };
+            audit.replay(index as u64, &block);
+        }
+    }
+
+    #[test]
+    fn audit_replays_balances_and_supply() {
+        let (minter, alice, bob, collector) = (account(0), account(1), account(2), account(3));
+        let mut mint = transaction(TransactionType::Mint, minter, alice, 1_000);
+        mint.fee = None;
+        let mut collected = transaction(TransactionType::Transfer, alice, bob, 100);
+        collected.fee_collector = Some(collector);
+        let burned = transaction(TransactionType::Transfer, alice, bob, 100);
+        let approve = transaction(TransactionType::Approve, alice, bob, 500);
+
+        let mut audit = audit();
+        replay_all(&mut audit, vec![mint, collected, burned, approve]);
+
+        assert_eq!(audit.replayed.get(&alice), Some(&(1_000 - 110 - 110 - 10)));
+        assert_eq!(audit.replayed.get(&bob), Some(&200));
+        assert_eq!(audit.replayed.get(&collector), Some(&10));
+        // Fees without a collector were burned.
+        assert_eq!(audit.report.replayed_supply, 1_000 - 10 - 10);
+        assert_eq!(audit.report.blocks_replayed, 4);
+        assert_eq!(audit.next_block, 4);
+        assert_eq!(audit.report.block_issue_count, 0);
+    }
+
+    #[test]
+    fn audit_flags_broken_links_and_overdrafts() {
+        let (alice, bob) = (account(1), account(2));
+        let mut audit = audit();
+        replay_all(&mut audit, vec![transaction(TransactionType::Transfer, alice, bob, 5)]);
+        assert_eq!(audit.report.block_issues[0].block_index, 0);
+
+        let unlinked = Block {
+            parent_hash: Some([0; 32]),
+            transaction: transaction(TransactionType::Mint, alice, bob, 5),
+        };
+        audit.replay(1, &unlinked);
+        assert_eq!(audit.report.block_issue_count, 2);
+        assert_eq!(audit.report.block_issues[1].block_index, 1);
+        assert_eq!(audit.replayed.get(&alice), Some(&0));
+    }
+
+    #[test]
+    fn upgrade_from_baseline_seeds_balance_checkpoints() {
+        let (minter, alice, bob) = (account(0), account(1), account(2));
+        // The baseline kept transactions in a map and balances without
+        // checkpoints.
+        let mut mint = transaction(TransactionType::Mint, minter, alice, 100);
+        mint.timestamp = 10;
+        let mut transfer = transaction(TransactionType::Transfer, alice, bob, 40);
+        transfer.timestamp = 20;
+        LEGACY_TRANSACTIONS.with(|transactions| {
+            let mut transactions = transactions.borrow_mut();
+            transactions.insert("tx_mint".to_string(), mint);
+            transactions.insert("tx_transfer".to_string(), transfer);
+        });
+        BALANCES.with(|balances| {
+            let mut balances = balances.borrow_mut();
+            balances.insert(alice, TokenBalance { owner: alice.owner, balance: 60, updated_at: 20 });
+            balances.insert(bob, TokenBalance { owner: bob.owner, balance: 40, updated_at: 20 });
+        });
+
+        migrate_stable_state();
+
+        assert_eq!(last_block_index(), Some(1));
+        assert_eq!(balance_at_block(alice, 1), 60);
+        assert_eq!(balance_at_block(bob, 1), 40);
+        assert_eq!(balance_at(alice, 25), 60);
+        assert!(LEGACY_TRANSACTIONS.with(|transactions| transactions.borrow().is_empty()));
+
+        // A later upgrade leaves the seeded checkpoints alone but still
+        // covers balances that have none.
+        let carol = account(3);
+        BALANCES.with(|balances| {
+            balances.borrow_mut().insert(carol, TokenBalance { owner: carol.owner, balance: 5, updated_at: 30 });
+        });
+        migrate_stable_state();
+        assert_eq!(balance_at_block(carol, 1), 5);
+        assert_eq!(balance_at_block(alice, 1), 60);
+    }
+
+    #[test]
+    fn csv_field_quotes_and_defuses_formulas() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("=SUM(A1)"), "'=SUM(A1)");
+        assert_eq!(csv_field("-1,2"), "\"'-1,2\"");
+        assert_eq!(csv_field(""), "");
+    }
+
+    #[test]
+    fn statement_rows_follow_the_account() {
+        let (alice, bob) = (account(1), account(2));
+        let sent = statement_entry(alice, 0, transaction(TransactionType::Transfer, alice, bob, 50)).unwrap();
+        assert_eq!((sent.direction.as_str(), sent.amount, sent.fee), ("out", 50, 10));
+        let received = statement_entry(bob, 0, transaction(TransactionType::Transfer, alice, bob, 50)).unwrap();
+        assert_eq!((received.direction.as_str(), received.amount, received.fee), ("in", 50, 0));
+        assert_eq!(received.counterparty, alice.owner);
+    }
+
+    #[test]
+    fn approvals_only_show_the_fee_to_the_owner() {
+        let (owner, spender) = (account(1), account(2));
+        let approve = transaction(TransactionType::Approve, owner, spender, 1_000);
+        let row = statement_entry(owner, 0, approve.clone()).unwrap();
+        assert_eq!((row.direction.as_str(), row.amount, row.fee), ("out", 0, 10));
+        assert!(statement_entry(spender, 0, approve).is_none());
+    }
+
+    #[test]
+    fn spender_only_blocks_are_left_out() {
+        let (owner, payee, spender) = (account(1), account(2), account(3));
+        let mut transfer = transaction(TransactionType::Transfer, owner, payee, 50);
+        transfer.spender = Some(spender);
+        assert!(statement_entry(spender, 0, transfer).is_none());
+    }
+
+    #[test]
+    fn archived_blocks_are_listed_in_the_csv() {
+        let archive = Principal::from_slice(&[9; 29]);
+        let statement = Statement {
+            account: account(1).owner,
+            subaccount: None,
+            from: 0,
+            to: u64::MAX,
+            entries: Vec::new(),
+            archived: vec![ArchivedTransactions {
+                canister_id: archive,
+                block_indices: vec![3, 7],
+            }],
+            next: None,
+        };
+        let csv = String::from_utf8(statement_csv(&statement)).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        assert_eq!(rows, vec![
+            format!("3,,,archived,,{},,,,,", archive.to_text()),
+            format!("7,,,archived,,{},,,,,", archive.to_text()),
+        ]);
+    }
+}
@@ -35,6 +35,7 @@ interface Course {
   sections: CourseSection[];
   created_at: number;
   token_reward: number;
+  price?: number | null;
   published: boolean;
   difficulty?: string;
   duration?: string;
@@ -49,6 +50,13 @@ interface Enrollment {
   progress: string[];
   completed: boolean;
   completed_at?: number;
+  payment?: {
+    amount: number;
+    educator_amount: number;
+    treasury_amount: number;
+    payment_block: number;
+    payout_batch_id: string;
+  } | null;
 }
 
 const CourseDetail: React.FC = () => {
@@ -105,8 +113,14 @@ const CourseDetail: React.FC = () => {
     try {
       setIsEnrolling(true);
       if (agentService.student) {
-        const newEnrollment = await agentService.student.enroll_in_course(courseId);
-        setEnrollment(newEnrollment);
+        // Enrollment waits on the course payment, so it can fail after the
+        // call is made, e.g. when the approval does not cover the price.
+        const result = await agentService.student.enroll_in_course(courseId);
+        if (result && 'err' in result) {
+          toast.error(result.err);
+          return;
+        }
+        setEnrollment(result && 'ok' in result ? result.ok : result);
         toast.success('Successfully enrolled in course!');
       }
     } catch (error) {
@@ -164,25 +178,16 @@ const CourseDetail: React.FC = () => {
         throw new Error('Student service not available');
       }
       
-      const certificate = await agentService.student.complete_course(courseId, course.title);
+      const certificate = await agentService.student.complete_course(courseId);
       console.log('=== CERTIFICATE CREATED ===', certificate);
       
-      // Step 2: Award tokens
-      if (!agentService.token) {
-        throw new Error('Token service not available');
-      }
-      
-      const principal = agentService.getPrincipal()?.toText();
-      if (!principal) {
-        throw new Error('No principal found');
+      // Step 2: Award tokens. The student canister pays the reward, since only
+      // token minters may; claiming again after a paid reward is a no-op.
+      const rewardResult = await agentService.student.claim_completion_reward(courseId);
+      if ('err' in rewardResult) {
+        throw new Error(`Course completed, but the reward was not paid: ${rewardResult.err}`);
       }
-      
-      const tokenResult = await agentService.token.reward_course_completion(
-        principal,
-        course.token_reward,
-        courseId
-      );
-      console.log('=== TOKENS AWARDED ===', tokenResult);
+      console.log('=== TOKENS AWARDED ===');
       
       // Update enrollment status locally
       setEnrollment(prev => prev ? {
@@ -532,7 +537,11 @@ const CourseDetail: React.FC = () => {
                       disabled={isEnrolling}
                       className="w-full bg-indigo-600 hover:bg-indigo-700 disabled:opacity-50 text-white py-2 px-4 rounded-md font-medium transition-colors duration-200"
                     >
-                      {isEnrolling ? 'Enrolling...' : 'Enroll Now'}
+                      {isEnrolling
+                        ? 'Enrolling...'
+                        : course.price
+                          ? `Enroll for ${course.price} tokens`
+                          : 'Enroll Now'}
                     </button>
                   </div>
                 )}
@@ -95,6 +95,7 @@ const PeerExchange: React.FC = () => {
   const handleTipNote = async (noteId: string, amount: number) => {
     try {
       if (agentService.peer) {
+        await agentService.approvePeerTip(amount);
         await agentService.peer.tip_peer_note(noteId, amount, 'Great contribution!');
         toast.success('Tip sent successfully!');
         // Refresh all notes to show updated tip count
@@ -84,13 +84,28 @@ class AgentService {
         return sharedStorage.getStudentProfile(principal);
       },
       enroll_in_course: async (courseId: string) => {
+        const principal = this.identity?.getPrincipal().toText() || 'mock-principal';
+        if (sharedStorage.getStudentEnrollments(principal).some((e: any) => e.course_id === courseId)) {
+          return { err: 'Already enrolled in this course' };
+        }
+
+        // Paid courses are charged through the token canister before the
+        // enrollment is stored; the mock only records the price.
+        const price = sharedStorage.getCourse(courseId)?.price ?? 0;
         const enrollment = {
-          student_id: this.identity?.getPrincipal().toText() || 'mock-principal',
+          student_id: principal,
           course_id: courseId,
           enrolled_at: Date.now() * 1000000,
           progress: [],
           completed: false,
-          completed_at: null
+          completed_at: null,
+          payment: price > 0 ? {
+            amount: price,
+            educator_amount: Math.floor(price * 0.8),
+            treasury_amount: price - Math.floor(price * 0.8),
+            payment_block: 0,
+            payout_batch_id: `batch_${Date.now()}`
+          } : null
         };
         
         // Store in shared storage
@@ -125,8 +140,17 @@ class AgentService {
         
         throw new Error('Enrollment not found');
       },
-      complete_course: async (courseId: string, courseTitle: string) => {
+      complete_course: async (courseId: string) => {
         const principal = this.identity?.getPrincipal().toText() || 'mock-principal';
+        const course = sharedStorage.getCourse(courseId);
+        const enrollment = sharedStorage.getStudentEnrollments(principal).find((e: any) => e.course_id === courseId);
+        if (!enrollment) {
+          throw new Error('Enrollment not found');
+        }
+        const remaining = (course?.sections || []).filter((s: any) => !enrollment.progress.includes(s.id));
+        if (remaining.length > 0) {
+          throw new Error(`${remaining.length} section(s) of this course are not complete`);
+        }
         
         // Update enrollment
         sharedStorage.updateEnrollment(principal, courseId, {
@@ -143,7 +167,7 @@ class AgentService {
           certificate_id: Math.random().toString(36).substr(2, 9),
           student_id: principal,
           course_id: courseId,
-          course_title: courseTitle,
+          course_title: course?.title || '',
           completion_date: Date.now() * 1000000,
           student_name: studentName
         };
@@ -152,6 +176,19 @@ class AgentService {
         sharedStorage.addCertificate(certificate);
         
         return certificate;
+      },
+      claim_completion_reward: async (courseId: string) => {
+        // The student canister asks the token canister to pay; a reward is only paid once
+        const principal = this.identity?.getPrincipal().toText() || 'mock-principal';
+        const enrollment = sharedStorage.getStudentEnrollments(principal).find((e: any) => e.course_id === courseId);
+        if (!enrollment?.completed) {
+          return { err: 'Course has not been completed' };
+        }
+        const memo = `Course completion reward: ${courseId}`;
+        if (!sharedStorage.getTransactions(principal).some((tx: any) => tx.memo === memo)) {
+          await this.tokenActor.reward_course_completion(principal, courseId);
+        }
+        return { ok: null };
       }
     };
 
@@ -175,7 +212,7 @@ class AgentService {
         const principal = this.identity?.getPrincipal().toText() || 'mock-principal';
         return sharedStorage.getEducatorProfile(principal);
       },
-      create_course: async (title: string, description: string, token_reward: number) => {
+      create_course: async (title: string, description: string, token_reward: number, price: number | null = null) => {
         const course = {
           id: Math.random().toString(36).substr(2, 9),
           title,
@@ -185,7 +222,8 @@ class AgentService {
           created_at: Date.now() * 1000000,
           updated_at: Date.now() * 1000000,
           published: false,
-          token_reward
+          token_reward,
+          price
         };
         
         // Store in shared storage
@@ -1312,6 +1350,12 @@ Remember: Security is an ongoing process, not a one-time check!`,
         const owner = principal || this.identity?.getPrincipal().toText() || 'mock-principal';
         return sharedStorage.getTokenBalance(owner);
       },
+      icrc1_fee: async () => {
+        return BigInt(0);
+      },
+      icrc2_approve: async (_args: any) => {
+        return { Ok: BigInt(0) };
+      },
       transfer_tokens: async (to: string, amount: number, memo: string) => {
         return {
           id: Math.random().toString(36).substr(2, 9),
@@ -1327,7 +1371,10 @@ Remember: Security is an ongoing process, not a one-time check!`,
         const principal = this.identity?.getPrincipal().toText() || 'mock-principal';
         return sharedStorage.getTransactions(principal);
       },
-      reward_course_completion: async (studentId: string, amount: number, courseId: string) => {
+      reward_course_completion: async (studentId: string, courseId: string) => {
+        // The reward amount comes from the course, not the caller
+        const amount = sharedStorage.getCourse(courseId)?.token_reward ?? 0;
+
         // Update token balance in shared storage
         const currentBalance = sharedStorage.getTokenBalance(studentId);
         const newBalance = currentBalance + amount;
@@ -1461,9 +1508,32 @@ Remember: Security is an ongoing process, not a one-time check!`,
     return this.peerActor;
   }
 
+  // Tips are paid by the peer canister out of an ICRC-2 allowance, so the
+  // tipper first approves it for the amount plus the ledger's transfer fee.
+  async approvePeerTip(amount: number): Promise<void> {
+    if (!this.tokenActor) {
+      throw new Error('Services not initialized');
+    }
+
+    const fee = await this.tokenActor.icrc1_fee();
+    const result = await this.tokenActor.icrc2_approve({
+      from_subaccount: [],
+      spender: { owner: Principal.fromText(peerCanisterId), subaccount: [] },
+      amount: BigInt(amount) + BigInt(fee),
+      expected_allowance: [],
+      expires_at: [],
+      fee: [],
+      memo: [],
+      created_at_time: []
+    });
+    if ('Err' in result) {
+      throw new Error(`Approval failed: ${JSON.stringify(result.Err, (_, value) => typeof value === 'bigint' ? value.toString() : value)}`);
+    }
+  }
+
   // Enhanced method to complete course with proper token rewards
-  async completeCourseWithRewards(courseId: string, courseTitle: string, tokenReward: number): Promise<any> {
-    if (!this.studentActor || !this.tokenActor || !this.identity) {
+  async completeCourseWithRewards(courseId: string): Promise<any> {
+    if (!this.studentActor || !this.identity) {
       throw new Error('Services not initialized');
     }
 
@@ -1471,17 +1541,16 @@ Remember: Security is an ongoing process, not a one-time check!`,
       console.log('Starting course completion process...');
       
       // 1. Complete the course in student canister
-      const certificate = await this.studentActor.complete_course(courseId, courseTitle);
+      const certificate = await this.studentActor.complete_course(courseId);
       console.log('Course completed, certificate generated:', certificate);
       
-      // 2. Award tokens to the student
-      const principal = this.identity.getPrincipal().toText();
-      const tokenResult = await this.tokenActor.reward_course_completion(
-        principal,
-        tokenReward,
-        courseId
-      );
-      console.log('Tokens awarded:', tokenResult);
+      // 2. Award tokens to the student (paid by the student canister, which is a token minter)
+      const rewardResult = await this.studentActor.claim_completion_reward(courseId);
+      if ('err' in rewardResult) {
+        console.warn('Completion reward not paid:', rewardResult.err);
+      } else {
+        console.log('Tokens awarded');
+      }
 
       // 3. Force refresh token balance in UI
       setTimeout(() => {